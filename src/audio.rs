@@ -3,18 +3,28 @@
 
 use std::process::Command;
 use serde::{Serialize, Deserialize};
-use regex::Regex;
-use crate::Quality;
+use crate::{Quality, Operation};
+use crate::tools::ToolPaths;
 
 #[derive(Debug, Clone, Copy, PartialEq, Hash)]
 #[derive(Serialize, Deserialize)]
 #[derive(EnumIter, AsRefStr, EnumString)]
 pub enum ACodec {
+    /// Chosen to suit the container, as `Container::default_acodec`
+    Auto,
     Copy,
     Opus,
+    Aac,
+    Vorbis,
+    Mp3,
+    /// Lossless, in Mkv.  The quality sets how hard it is compressed,
+    /// which changes the size but not the sound.
+    Flac,
+    /// Uncompressed 16 bit PCM, in Mkv or Mov.  The quality is ignored.
+    PcmS16,
 }
 
-/// loudnorm: http://k.ylo.ph/2016/04/04/loudnorm.html
+// loudnorm: http://k.ylo.ph/2016/04/04/loudnorm.html
 
 /// LUFS
 ///    This is "I", the Integrated Loudness Target (range -70 through -5, default -24)
@@ -22,18 +32,45 @@ pub enum ACodec {
 ///          -16 (maximum) for best results.
 ///      -20 gives the greatest dynamic range and the least processing.
 ///      -16 gives the most loudness
-pub const LOUDNORM_LUFS: &'static str = "-19";
+pub const LOUDNORM_LUFS: &str = "-19";
 
 /// TP (limiter threshold peak) is the level of the true peak.  This is recommended to -1.0
 /// so as not to clip, or some do -1.5.  Don't do 0.  Default is -2.0.
-pub const LOUDNORM_TP: &'static str = "-1.0";
+pub const LOUDNORM_TP: &str = "-1.0";
 
 /// LRA is Loudness Range target (1.0 - 20.0), is the variation in loudness on a
 /// macroscopic scale.  Default is 7. Other references tend to use 11.
-pub const LOUDNORM_LRA: &'static str = "9";
+pub const LOUDNORM_LRA: &str = "9";
+
+/// The loudnorm targets, from the operation or else the defaults above
+#[derive(Debug, Clone)]
+pub struct LoudnormTargets {
+    pub i: String,
+    pub tp: String,
+    pub lra: String,
+    /// Linear (rather than dynamic) normalization was requested
+    pub linear: bool,
+}
+
+impl LoudnormTargets {
+    pub fn from_operation(operation: &Operation) -> LoudnormTargets {
+        let target = |value: Option<f32>, default: &str| value
+            .map(|v| format!("{}", v))
+            .unwrap_or_else(|| default.to_string());
+        LoudnormTargets {
+            i: target(operation.loudnorm_i, LOUDNORM_LUFS),
+            tp: target(operation.loudnorm_tp, LOUDNORM_TP),
+            lra: target(operation.loudnorm_lra, LOUDNORM_LRA),
+            linear: operation.loudnorm_linear,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Loudnorm {
+    /// Targets that the measurements were taken against
+    pub targets: LoudnormTargets,
+
     /// Measured input_i
     pub input_i: String,
 
@@ -51,102 +88,364 @@ pub struct Loudnorm {
 }
 
 impl Loudnorm {
-    pub fn from_analyze(input_file: &str, cpulimit: u32) -> Loudnorm {
-        let mut command = Command::new(crate::CPULIMIT_PATH);
-        command.arg("-l").arg(&*format!("{}", cpulimit))
-            .arg(crate::FFMPEG_PATH)
-            .arg("-y")
-            .arg("-i").arg(input_file)
-            .arg("-af")
-            .arg(&*Loudnorm::analyze_af())
+    pub fn from_analyze(tools: &ToolPaths, operation: &Operation, input_file: &str)
+                        -> Result<Loudnorm, Box<dyn std::error::Error>>
+    {
+        let dry_run = operation.dry_run;
+        let mut command = crate::ffmpeg_cmd(tools, operation.cpulimit);
+        command.arg("-y");
+        crate::input_args(&mut command, operation, input_file);
+        if let Some(track) = operation.source_audio_track() {
+            command.arg("-map").arg(format!("0:a:{}", track));
+        }
+        // Measure what loudnorm will actually be given
+        let mut filters: Vec<String> = pre_loudnorm_filters(operation);
+        let targets = LoudnormTargets::from_operation(operation);
+        filters.push(Loudnorm::analyze_af(&targets));
+        command.arg("-af")
+            .arg(filters.join(","))
             .arg("-f").arg("null").arg("-");
 
-        let stderr_str = crate::run_cmd(command);
-        Loudnorm::from_analyze_data(&*stderr_str)
+        let stderr_str = crate::run::run_cmd(command, dry_run, operation.timeout())?;
+        if dry_run {
+            return Ok(Loudnorm::placeholder(targets));
+        }
+        Loudnorm::from_analyze_data(&stderr_str, targets)
+    }
+
+    /// Stand-in values for a dry run, where nothing was measured
+    fn placeholder(targets: LoudnormTargets) -> Loudnorm {
+        Loudnorm {
+            targets,
+            input_i: "MEASURED_I".to_string(),
+            input_lra: "MEASURED_LRA".to_string(),
+            input_tp: "MEASURED_TP".to_string(),
+            input_thresh: "MEASURED_THRESH".to_string(),
+            target_offset: "OFFSET".to_string(),
+        }
     }
 
-    fn analyze_af() -> String {
+    fn analyze_af(targets: &LoudnormTargets) -> String {
         format!("loudnorm=I={I}:TP={TP}:LRA={LRA}:print_format=json",
-                I=LOUDNORM_LUFS, TP=LOUDNORM_TP, LRA=LOUDNORM_LRA)
+                I=targets.i, TP=targets.tp, LRA=targets.lra)
     }
 
-    fn from_analyze_data(data: &str) -> Loudnorm {
-        let mut loudnorm = Loudnorm {
-            input_i: "".to_string(),
-            input_lra: "".to_string(),
-            input_tp: "".to_string(),
-            input_thresh: "".to_string(),
-            target_offset: "".to_string(),
-        };
+    fn from_analyze_data(data: &str, targets: LoudnormTargets)
+                         -> Result<Loudnorm, Box<dyn std::error::Error>>
+    {
+        // The JSON block is the last thing loudnorm prints, after its
+        // "[Parsed_loudnorm_0 @ ...]" line
+        let json = data.rfind("\"input_i\"")
+            .and_then(|key| {
+                let start = data[..key].rfind('{')?;
+                let end = key + data[key..].find('}')?;
+                Some(&data[start..=end])
+            })
+            .ok_or("Did not find the loudnorm analysis JSON in ffmpeg's output")?;
 
-        let input_i_re = Regex::new(r##""input_i" : "(-?\d+.\d+)""##).unwrap();
-        for cap in input_i_re.captures_iter(data) {
-            loudnorm.input_i = cap[1].to_owned();
-        }
-        if loudnorm.input_i.is_empty() {
-            panic!("Did not find input_i");
-        }
+        let measured: LoudnormJson = serde_json::from_str(json)
+            .map_err(|e| format!("Could not parse loudnorm analysis: {}\n{}", e, json))?;
 
-        let input_lra_re = Regex::new(r##""input_lra" : "(-?\d+.\d+)""##).unwrap();
-        for cap in input_lra_re.captures_iter(data) {
-            loudnorm.input_lra = cap[1].to_owned();
-        }
-        if loudnorm.input_lra.is_empty() {
-            panic!("Did not find input_lra");
-        }
+        let loudnorm = Loudnorm {
+            targets,
+            input_i: finite("input_i", measured.input_i)?,
+            input_lra: finite("input_lra", measured.input_lra)?,
+            input_tp: finite("input_tp", measured.input_tp)?,
+            input_thresh: finite("input_thresh", measured.input_thresh)?,
+            target_offset: finite("target_offset", measured.target_offset)?,
+        };
 
-        let input_tp_re = Regex::new(r##""input_tp" : "(-?\d+.\d+)""##).unwrap();
-        for cap in input_tp_re.captures_iter(data) {
-            loudnorm.input_tp = cap[1].to_owned();
-        }
-        if loudnorm.input_tp.is_empty() {
-            panic!("Did not find input_tp");
+        if crate::log::enabled(crate::log::LogLevel::Verbose) {
+            println!("LOUDNORM DATA IS: {:?}", loudnorm);
         }
 
-        let input_thresh_re = Regex::new(r##""input_thresh" : "(-?\d+.\d+)""##).unwrap();
-        for cap in input_thresh_re.captures_iter(data) {
-            loudnorm.input_thresh = cap[1].to_owned();
-        }
-        if loudnorm.input_thresh.is_empty() {
-            panic!("Did not find input_thresh");
-        }
+        Ok(loudnorm)
+    }
 
-        let target_offset_re = Regex::new(r##""target_offset" : "(-?\d+.\d+)""##).unwrap();
-        for cap in target_offset_re.captures_iter(data) {
-            loudnorm.target_offset = cap[1].to_owned();
+    /// Warn if loudnorm was asked for linear normalization but had to fall
+    /// back to dynamic, since the result may then miss the targets.  This
+    /// is reported in the summary it prints during the encode.
+    pub fn check_fallback(&self, stderr: &str) {
+        if !self.targets.linear {
+            return;
         }
-        if loudnorm.target_offset.is_empty() {
-            panic!("Did not find target_offset");
+        let dynamic = stderr.lines()
+            .any(|line| line.contains("Normalization Type") && line.contains("Dynamic"));
+        if dynamic {
+            println!("WARNING: loudnorm fell back to dynamic normalization because \
+                      the measured values did not permit linear normalization.  \
+                      The output loudness may differ from the target.");
         }
+    }
 
-        println!("LOUDNORM DATA IS: {:?}", loudnorm);
-
-        loudnorm
+    /// What loudnorm reports the output measured, in the summary it prints
+    /// during the encode.  (It prints nothing when ffmpeg is logging only
+    /// errors.)
+    pub fn output_loudness(stderr: &str) -> Option<OutputLoudness> {
+        let value = |label: &str| stderr.lines()
+            .find_map(|line| line.trim().strip_prefix(label))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|number| number.parse::<f64>().ok());
+        Some(OutputLoudness {
+            i: value("Output Integrated:")?,
+            tp: value("Output True Peak:")?,
+            lra: value("Output LRA:")?,
+        })
     }
 
     pub fn convert_af(&self) -> String {
-        format!("loudnorm=I={I}:TP={TP}:LRA={LRA}:measured_I={measured_I}:measured_LRA={measured_LRA}:measured_TP={measured_TP}:measured_thresh={measured_thresh}:offset={offset}:linear=true:print_format=summary",
-                I=LOUDNORM_LUFS,
-                TP=LOUDNORM_TP,
-                LRA=LOUDNORM_LRA,
+        format!("loudnorm=I={I}:TP={TP}:LRA={LRA}:measured_I={measured_I}:measured_LRA={measured_LRA}:measured_TP={measured_TP}:measured_thresh={measured_thresh}:offset={offset}:linear={linear}:print_format=summary",
+                I=self.targets.i,
+                TP=self.targets.tp,
+                LRA=self.targets.lra,
                 measured_I=self.input_i,
                 measured_LRA=self.input_lra,
                 measured_TP=self.input_tp,
                 measured_thresh=self.input_thresh,
-                offset=self.target_offset)
+                offset=self.target_offset,
+                linear=self.targets.linear)
     }
 }
 
-pub fn opus(command: &mut Command, quality: Quality) {
-    let bitrate = match quality {
+/// The loudness of the output, as loudnorm measured it while encoding
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct OutputLoudness {
+    /// Integrated loudness in LUFS
+    pub i: f64,
+    /// True peak in dBTP
+    pub tp: f64,
+    /// Loudness range in LU
+    pub lra: f64,
+}
+
+/// The measurements loudnorm prints with print_format=json.  Values are
+/// strings, and may be "inf" or "-inf" (e.g. for silence).
+#[derive(Deserialize)]
+struct LoudnormJson {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+/// Check a measurement is a finite number, since loudnorm cannot be given
+/// an infinite one
+fn finite(name: &str, value: String) -> Result<String, Box<dyn std::error::Error>> {
+    match value.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() => Ok(value),
+        Ok(_) => Err(format!("Loudnorm measured {} as {}, which usually means \
+                              the audio is silent.  Turn off loudnorm for this input.",
+                             name, value).into()),
+        Err(_) => Err(format!("Loudnorm measured {} as {}, which is not a number",
+                              name, value).into()),
+    }
+}
+
+/// Audio filters that come before loudnorm in the chain
+pub fn pre_loudnorm_filters(operation: &Operation) -> Vec<String> {
+    let mut filters: Vec<String> = Vec::new();
+    // Stretched or squeezed to the timestamps, for a variable frame rate
+    // source whose audio drifts from them
+    if operation.vfr_sync {
+        filters.push("aresample=async=1".to_string());
+    }
+    // Shifted first, in the source's own time
+    match operation.audio_offset_ms {
+        Some(ms) if ms > 0 => filters.push(format!("adelay={}:all=1", ms)),
+        Some(ms) if ms < 0 => filters.push(format!("atrim=start={},asetpts=PTS-STARTPTS",
+                                                   -ms as f64 / 1000.0)),
+        _ => { },
+    }
+    if let Some(ref pan) = operation.pan {
+        filters.push(format!("pan={}", pan));
+    }
+    if let Some(db) = operation.volume_db {
+        filters.push(format!("volume={}dB", db));
+    }
+    if let Some(speed) = operation.speed {
+        filters.extend(atempo_filters(speed));
+    }
+    filters
+}
+
+/// atempo only accepts factors from 0.5 to 2.0, so larger changes are made
+/// as a chain of them
+fn atempo_filters(speed: f64) -> Vec<String> {
+    let mut filters: Vec<String> = Vec::new();
+    let mut remaining = speed;
+    while remaining > 2.0 {
+        filters.push("atempo=2.0".to_string());
+        remaining /= 2.0;
+    }
+    while remaining < 0.5 {
+        filters.push("atempo=0.5".to_string());
+        remaining /= 0.5;
+    }
+    filters.push(format!("atempo={}", remaining));
+    filters
+}
+
+impl ACodec {
+    /// The encoder ffmpeg uses for the codec
+    pub fn encoder(&self) -> Option<&'static str> {
+        match *self {
+            ACodec::Auto | ACodec::Copy => None,
+            ACodec::Opus => Some("libopus"),
+            ACodec::Aac => Some("aac"),
+            ACodec::Vorbis => Some("libvorbis"),
+            ACodec::Mp3 => Some("libmp3lame"),
+            ACodec::Flac => Some("flac"),
+            ACodec::PcmS16 => Some("pcm_s16le"),
+        }
+    }
+
+    /// The file extension for the audio on its own
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            ACodec::Auto | ACodec::Copy => "mka",
+            ACodec::Opus => "opus",
+            ACodec::Aac => "m4a",
+            ACodec::Vorbis => "ogg",
+            ACodec::Mp3 => "mp3",
+            ACodec::Flac => "flac",
+            ACodec::PcmS16 => "wav",
+        }
+    }
+}
+
+/// Audio bitrate in kbps.  This is only approximate for VBR codecs.
+pub fn bitrate(codec: ACodec, quality: Quality) -> Option<u32> {
+    match codec {
+        ACodec::Auto | ACodec::Copy => None,
+        ACodec::Opus => Some(opus_bitrate(quality)),
+        ACodec::Aac => Some(aac_bitrate(quality)),
+        ACodec::Vorbis => Some(vorbis_bitrate(quality)),
+        ACodec::Mp3 => Some(mp3_bitrate(quality)),
+        ACodec::Flac => Some(flac_bitrate(quality)),
+        ACodec::PcmS16 => Some(PCM_S16_BITRATE),
+    }
+}
+
+fn opus_bitrate(quality: Quality) -> u32 {
+    match quality {
         Quality::VeryLow => 16,
         Quality::Low => 24,
         Quality::Medium => 32,
         Quality::High => 64,
         Quality::VeryHigh => 96
-    };
+    }
+}
 
+pub fn opus(command: &mut Command, quality: Quality) {
     command
         .arg("-c:a").arg("libopus")
-        .arg("-b:a").arg(&*format!("{}k",bitrate));
+        .arg("-b:a").arg(&*format!("{}k",opus_bitrate(quality)));
+}
+
+fn aac_bitrate(quality: Quality) -> u32 {
+    match quality {
+        Quality::VeryLow => 64,
+        Quality::Low => 96,
+        Quality::Medium => 128,
+        Quality::High => 192,
+        Quality::VeryHigh => 256
+    }
+}
+
+pub fn aac(command: &mut Command, quality: Quality) {
+    command
+        .arg("-c:a").arg("aac")
+        .arg("-b:a").arg(&*format!("{}k",aac_bitrate(quality)));
+}
+
+// Nominal bitrates of the vorbis_quality() levels below
+fn vorbis_bitrate(quality: Quality) -> u32 {
+    match quality {
+        Quality::VeryLow => 80,
+        Quality::Low => 112,
+        Quality::Medium => 128,
+        Quality::High => 192,
+        Quality::VeryHigh => 256
+    }
+}
+
+// libvorbis VBR quality, 0-10
+fn vorbis_quality(quality: Quality) -> u8 {
+    match quality {
+        Quality::VeryLow => 1,
+        Quality::Low => 3,
+        Quality::Medium => 4,
+        Quality::High => 6,
+        Quality::VeryHigh => 8
+    }
+}
+
+pub fn vorbis(command: &mut Command, quality: Quality) {
+    command
+        .arg("-c:a").arg("libvorbis")
+        .arg("-q:a").arg(&*format!("{}",vorbis_quality(quality)));
+}
+
+// Nominal bitrates of the mp3_quality() levels below
+fn mp3_bitrate(quality: Quality) -> u32 {
+    match quality {
+        Quality::VeryLow => 100,
+        Quality::Low => 130,
+        Quality::Medium => 165,
+        Quality::High => 190,
+        Quality::VeryHigh => 245
+    }
+}
+
+// LAME VBR quality, 0 (best) through 9 (worst)
+fn mp3_quality(quality: Quality) -> u8 {
+    match quality {
+        Quality::VeryLow => 7,
+        Quality::Low => 5,
+        Quality::Medium => 4,
+        Quality::High => 2,
+        Quality::VeryHigh => 0
+    }
+}
+
+pub fn mp3(command: &mut Command, quality: Quality) {
+    command
+        .arg("-c:a").arg("libmp3lame")
+        .arg("-q:a").arg(&*format!("{}",mp3_quality(quality)));
+}
+
+// Typical for 48kHz stereo, though it depends greatly on the content
+fn flac_bitrate(quality: Quality) -> u32 {
+    match quality {
+        Quality::VeryLow => 900,
+        Quality::Low => 870,
+        Quality::Medium => 850,
+        Quality::High => 840,
+        Quality::VeryHigh => 835
+    }
+}
+
+// 0 (fastest, largest) through 8 (slowest, smallest)
+fn flac_compression_level(quality: Quality) -> u8 {
+    match quality {
+        Quality::VeryLow => 0,
+        Quality::Low => 2,
+        Quality::Medium => 5,
+        Quality::High => 7,
+        Quality::VeryHigh => 8
+    }
+}
+
+pub fn flac(command: &mut Command, quality: Quality) {
+    command
+        .arg("-c:a").arg("flac")
+        .arg("-compression_level").arg(&*format!("{}",flac_compression_level(quality)));
+}
+
+// 48kHz stereo
+const PCM_S16_BITRATE: u32 = 48 * 16 * 2;
+
+pub fn pcm_s16(command: &mut Command) {
+    command.arg("-c:a").arg("pcm_s16le");
 }
@@ -0,0 +1,78 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::fs;
+use std::path::PathBuf;
+use crate::run;
+
+/// Removes intermediate files when dropped, but only if the run was marked
+/// as successful, so that they remain for debugging after a failure.  They
+/// are also removed if we were interrupted, which is no failure to debug.
+pub struct Cleanup {
+    enabled: bool,
+    succeeded: bool,
+    files: Vec<PathBuf>,
+    prefixes: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+}
+
+impl Cleanup {
+    pub fn new(enabled: bool) -> Cleanup {
+        Cleanup {
+            enabled,
+            succeeded: false,
+            files: Vec::new(),
+            prefixes: Vec::new(),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Remove this file
+    pub fn file<P: Into<PathBuf>>(&mut self, path: P) {
+        self.files.push(path.into());
+    }
+
+    /// Remove every file in the directory whose name starts with the final
+    /// component of this path (e.g. ffmpeg's 2-pass logs)
+    pub fn prefix<P: Into<PathBuf>>(&mut self, path: P) {
+        self.prefixes.push(path.into());
+    }
+
+    /// Remove this directory, if it is empty once the files are gone
+    pub fn dir<P: Into<PathBuf>>(&mut self, path: P) {
+        self.dirs.push(path.into());
+    }
+
+    pub fn succeeded(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Cleanup {
+    fn drop(&mut self) {
+        if !self.enabled || !(self.succeeded || run::interrupted()) {
+            return;
+        }
+        for file in &self.files {
+            let _ = fs::remove_file(file);
+        }
+        for prefix in &self.prefixes {
+            let (dir, name) = match (prefix.parent(), prefix.file_name()) {
+                (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+                _ => continue,
+            };
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&*name) {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+        for dir in &self.dirs {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use serde::{Serialize, Deserialize};
+use crate::watermark::Position;
+
+/// Another input shown at the same time as the main picture, e.g. a webcam
+/// recorded alongside a screencast.  It is trimmed with the same start and
+/// duration, and its audio is not used.
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Composite {
+    /// The other input small, over the main picture
+    PictureInPicture {
+        input: String,
+        #[serde(default)]
+        position: Position,
+        /// Width of the inset in pixels
+        #[serde(default = "default_inset_width")]
+        width: u32,
+    },
+    /// The other input beside the main picture, scaled to the same height.
+    /// This ends with whichever input is shorter.
+    SideBySide {
+        input: String,
+        /// Put the other input on the left rather than the right
+        #[serde(default)]
+        left: bool,
+    },
+}
+
+fn default_inset_width() -> u32 {
+    320
+}
+
+impl Composite {
+    pub fn input(&self) -> &str {
+        match self {
+            Composite::PictureInPicture { input, .. } => input,
+            Composite::SideBySide { input, .. } => input,
+        }
+    }
+
+    /// The filters that fit the other input to the main picture, given
+    /// its height, playback speed and frame rate
+    pub fn input_filter(&self, height: u32, speed: Option<f64>, fps: (u32, u32)) -> String {
+        let mut filter = match self {
+            Composite::PictureInPicture { width, .. } => format!("scale={}:-2", width),
+            Composite::SideBySide { .. } => format!("scale=-2:{}", height),
+        };
+        filter.push_str(",setsar=1");
+        if let Some(speed) = speed {
+            filter.push_str(&format!(",setpts=PTS/{}", speed));
+        }
+        filter.push_str(&format!(",fps=fps={}/{}", fps.0, fps.1));
+        filter
+    }
+
+    /// The order the filter takes the [main] and [other] pictures in
+    pub fn labels(&self) -> &'static str {
+        match self {
+            // hstack takes its inputs left to right
+            Composite::SideBySide { left: true, .. } => "[other][main]",
+            _ => "[main][other]",
+        }
+    }
+
+    /// The filter that joins the two
+    pub fn filter(&self) -> String {
+        match self {
+            Composite::PictureInPicture { position, .. } => {
+                let (x, y) = position.xy("W", "H", "w", "h");
+                // The main picture carries on if the other ends first
+                format!("overlay={}:{}:eof_action=pass", x, y)
+            },
+            Composite::SideBySide { .. } => "hstack=shortest=1".to_string(),
+        }
+    }
+}
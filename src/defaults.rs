@@ -0,0 +1,220 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use serde::{Deserialize, Deserializer};
+use crate::{Quality, Container, Operation};
+use crate::video::{VCodec, KeyframeInterval, EncodeMode, HwAccel, FpsMode};
+use crate::audio::ACodec;
+use crate::tools::Tools;
+use crate::thumbnail::ThumbnailSpec;
+use crate::sprites::SpriteSpec;
+use crate::watermark::Watermark;
+use crate::mapping::StreamMapping;
+use crate::log::LogLevel;
+
+/// Settings shared by many operations.  Whatever is set here is used where
+/// an operation leaves the field out.  Values are written just as they
+/// would be in an operation.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OperationDefaults {
+    #[serde(deserialize_with = "present")]
+    pub cpulimit: Option<Option<u32>>,
+    #[serde(deserialize_with = "present")]
+    pub auto_rotate: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub scale: Option<(i32, i32)>,
+    #[serde(deserialize_with = "present")]
+    pub loudnorm: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub loudnorm_linear: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub loudnorm_i: Option<Option<f32>>,
+    #[serde(deserialize_with = "present")]
+    pub loudnorm_tp: Option<Option<f32>>,
+    #[serde(deserialize_with = "present")]
+    pub loudnorm_lra: Option<Option<f32>>,
+    #[serde(deserialize_with = "present")]
+    pub video_quality: Option<Quality>,
+    #[serde(deserialize_with = "present")]
+    pub video_fps: Option<(u32, u32)>,
+    #[serde(deserialize_with = "present")]
+    pub fps_mode: Option<FpsMode>,
+    #[serde(deserialize_with = "present")]
+    pub vfr_sync: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub keep_color: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub pix_fmt: Option<Option<String>>,
+    #[serde(deserialize_with = "present")]
+    pub video_codec: Option<VCodec>,
+    #[serde(deserialize_with = "present")]
+    pub keyframe_interval: Option<Option<KeyframeInterval>>,
+    #[serde(deserialize_with = "present")]
+    pub threads: Option<Option<u32>>,
+    #[serde(deserialize_with = "present")]
+    pub hwaccel: Option<Option<HwAccel>>,
+    #[serde(deserialize_with = "present")]
+    pub fallback_codec: Option<Option<VCodec>>,
+    #[serde(deserialize_with = "present")]
+    pub encode_mode: Option<EncodeMode>,
+    #[serde(deserialize_with = "present")]
+    pub target_size_mb: Option<Option<u32>>,
+    #[serde(deserialize_with = "present")]
+    pub crf: Option<Option<u8>>,
+    #[serde(deserialize_with = "present")]
+    pub pass1_speed: Option<Option<u8>>,
+    #[serde(deserialize_with = "present")]
+    pub pass2_speed: Option<Option<u8>>,
+    #[serde(deserialize_with = "present")]
+    pub minrate_pct: Option<u32>,
+    #[serde(deserialize_with = "present")]
+    pub maxrate_pct: Option<u32>,
+    #[serde(deserialize_with = "present")]
+    pub audio_quality: Option<Quality>,
+    #[serde(deserialize_with = "present")]
+    pub audio_codec: Option<ACodec>,
+    #[serde(deserialize_with = "present")]
+    pub channels: Option<Option<u8>>,
+    #[serde(deserialize_with = "present")]
+    pub stream_mapping: Option<StreamMapping>,
+    #[serde(deserialize_with = "present")]
+    pub strip_metadata: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub keep_chapters: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub keep_spaces: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub output_template: Option<Option<String>>,
+    #[serde(deserialize_with = "present")]
+    pub container: Option<Container>,
+    #[serde(deserialize_with = "present")]
+    pub overwrite: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub faststart: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub work_dir: Option<Option<PathBuf>>,
+    #[serde(deserialize_with = "present")]
+    pub output_dir: Option<Option<PathBuf>>,
+    #[serde(deserialize_with = "present")]
+    pub thumbnail: Option<Option<ThumbnailSpec>>,
+    #[serde(deserialize_with = "present")]
+    pub sprites: Option<Option<SpriteSpec>>,
+    #[serde(deserialize_with = "present")]
+    pub watermark: Option<Option<Watermark>>,
+    #[serde(deserialize_with = "present")]
+    pub cleanup: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub concat_reencode: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub tools: Option<Tools>,
+    #[serde(deserialize_with = "present")]
+    pub extra_args: Option<Vec<String>>,
+    #[serde(deserialize_with = "present")]
+    pub timeout_secs: Option<Option<u64>>,
+    #[serde(deserialize_with = "present")]
+    pub json_output: Option<bool>,
+    #[serde(deserialize_with = "present")]
+    pub log_level: Option<Option<LogLevel>>,
+}
+
+impl OperationDefaults {
+    pub fn load(path: &Path) -> Result<OperationDefaults, Box<dyn std::error::Error>> {
+        let mut buffer = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut buffer))
+            .map_err(|e| format!("Cannot read defaults {}: {}", path.display(), e))?;
+        ron::de::from_str(&buffer)
+            .map_err(|e| format!("Cannot parse defaults {}: {}", path.display(), e).into())
+    }
+
+    /// Where the defaults are looked for when no --config is given
+    pub fn default_path() -> Option<PathBuf> {
+        let config = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(config.join("prepvideo").join("defaults.ron"))
+    }
+
+    /// Set the fields of the operation that these defaults have values for
+    pub fn merge_into(self, operation: &mut Operation) {
+        if let Some(v) = self.cpulimit { operation.cpulimit = v; }
+        if let Some(v) = self.auto_rotate { operation.auto_rotate = v; }
+        if let Some(v) = self.scale { operation.scale = v; }
+        if let Some(v) = self.loudnorm { operation.loudnorm = v; }
+        if let Some(v) = self.loudnorm_linear { operation.loudnorm_linear = v; }
+        if let Some(v) = self.loudnorm_i { operation.loudnorm_i = v; }
+        if let Some(v) = self.loudnorm_tp { operation.loudnorm_tp = v; }
+        if let Some(v) = self.loudnorm_lra { operation.loudnorm_lra = v; }
+        if let Some(v) = self.video_quality { operation.video_quality = v; }
+        if let Some(v) = self.video_fps { operation.video_fps = v; }
+        if let Some(v) = self.fps_mode { operation.fps_mode = v; }
+        if let Some(v) = self.vfr_sync { operation.vfr_sync = v; }
+        if let Some(v) = self.keep_color { operation.keep_color = v; }
+        if let Some(v) = self.pix_fmt { operation.pix_fmt = v; }
+        if let Some(v) = self.video_codec { operation.video_codec = v; }
+        if let Some(v) = self.keyframe_interval { operation.keyframe_interval = v; }
+        if let Some(v) = self.threads { operation.threads = v; }
+        if let Some(v) = self.hwaccel { operation.hwaccel = v; }
+        if let Some(v) = self.fallback_codec { operation.fallback_codec = v; }
+        if let Some(v) = self.encode_mode { operation.encode_mode = v; }
+        if let Some(v) = self.target_size_mb { operation.target_size_mb = v; }
+        if let Some(v) = self.crf { operation.crf = v; }
+        if let Some(v) = self.pass1_speed { operation.pass1_speed = v; }
+        if let Some(v) = self.pass2_speed { operation.pass2_speed = v; }
+        if let Some(v) = self.minrate_pct { operation.minrate_pct = v; }
+        if let Some(v) = self.maxrate_pct { operation.maxrate_pct = v; }
+        if let Some(v) = self.audio_quality { operation.audio_quality = v; }
+        if let Some(v) = self.audio_codec { operation.audio_codec = v; }
+        if let Some(v) = self.channels { operation.channels = v; }
+        if let Some(v) = self.stream_mapping { operation.stream_mapping = v; }
+        if let Some(v) = self.strip_metadata { operation.strip_metadata = v; }
+        if let Some(v) = self.keep_chapters { operation.keep_chapters = v; }
+        if let Some(v) = self.keep_spaces { operation.keep_spaces = v; }
+        if let Some(v) = self.output_template { operation.output_template = v; }
+        if let Some(v) = self.container { operation.container = v; }
+        if let Some(v) = self.overwrite { operation.overwrite = v; }
+        if let Some(v) = self.faststart { operation.faststart = v; }
+        if let Some(v) = self.work_dir { operation.work_dir = v; }
+        if let Some(v) = self.output_dir { operation.output_dir = v; }
+        if let Some(v) = self.thumbnail { operation.thumbnail = v; }
+        if let Some(v) = self.sprites { operation.sprites = v; }
+        if let Some(v) = self.watermark { operation.watermark = v; }
+        if let Some(v) = self.cleanup { operation.cleanup = v; }
+        if let Some(v) = self.concat_reencode { operation.concat_reencode = v; }
+        if let Some(v) = self.tools { operation.tools = v; }
+        if let Some(v) = self.extra_args { operation.extra_args = v; }
+        if let Some(v) = self.timeout_secs { operation.timeout_secs = v; }
+        if let Some(v) = self.json_output { operation.json_output = v; }
+        if let Some(v) = self.log_level { operation.log_level = v; }
+    }
+}
+
+/// A field that is present has its value as it would be in an operation
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+static DEFAULTS: OnceLock<OperationDefaults> = OnceLock::new();
+
+/// Use these defaults for every operation read from now on
+pub fn set(defaults: OperationDefaults) {
+    let _ = DEFAULTS.set(defaults);
+}
+
+/// Where an operation leaves out a field, this is where its value comes
+/// from: the built in default, overridden by the defaults file
+pub fn default_operation() -> Operation {
+    let mut operation = Operation::default();
+    if let Some(defaults) = DEFAULTS.get() {
+        defaults.clone().merge_into(&mut operation);
+    }
+    operation
+}
@@ -0,0 +1,78 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use serde::{Serialize, Serializer, Deserialize};
+use serde::ser::SerializeStruct;
+
+/// An input file, optionally trimmed.  Written as just the path when the
+/// whole file is used, e.g. `"a.mp4"`, or as
+/// `(path: "b.mp4", start: Some(2.0))` to trim it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "PathOrClip")]
+pub struct InputClip {
+    pub path: String,
+    /// Seconds into this input to start from
+    pub start: Option<f64>,
+    /// Seconds into this input to end at
+    pub end: Option<f64>,
+}
+
+impl InputClip {
+    pub fn is_trimmed(&self) -> bool {
+        self.start.is_some() || self.end.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.start.is_some_and(|s| s < 0.0) {
+            return Err(format!("The start of {} cannot be negative", self.path).into());
+        }
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if end <= start {
+                return Err(format!("The end of {} must be after its start",
+                                   self.path).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<&str> for InputClip {
+    fn from(path: &str) -> InputClip {
+        InputClip { path: path.to_string(), start: None, end: None }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathOrClip {
+    Path(String),
+    Clip {
+        path: String,
+        #[serde(default)]
+        start: Option<f64>,
+        #[serde(default)]
+        end: Option<f64>,
+    },
+}
+
+impl From<PathOrClip> for InputClip {
+    fn from(input: PathOrClip) -> InputClip {
+        match input {
+            PathOrClip::Path(path) => InputClip { path, start: None, end: None },
+            PathOrClip::Clip { path, start, end } => InputClip { path, start, end },
+        }
+    }
+}
+
+impl Serialize for InputClip {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.is_trimmed() {
+            return serializer.serialize_str(&self.path);
+        }
+        let mut clip = serializer.serialize_struct("InputClip", 3)?;
+        clip.serialize_field("path", &self.path)?;
+        clip.serialize_field("start", &self.start)?;
+        clip.serialize_field("end", &self.end)?;
+        clip.end()
+    }
+}
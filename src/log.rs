@@ -0,0 +1,65 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::cell::Cell;
+use serde::{Serialize, Deserialize};
+
+/// How much is printed.  Warnings, errors and the JSON summary are always
+/// printed.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Hash, Default)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum LogLevel {
+    /// Nothing else.  ffmpeg is also told to log only errors, which means
+    /// it does not report whether loudnorm had to fall back to dynamic
+    /// normalization.
+    Quiet,
+    /// The commands run, and their progress
+    #[default]
+    Normal,
+    /// Also the operation, measurements and calculations, and everything
+    /// ffmpeg logs
+    Verbose,
+}
+
+// Per thread, so that operations run in parallel each have their own
+thread_local! {
+    static LEVEL: Cell<LogLevel> = const { Cell::new(LogLevel::Normal) };
+}
+
+/// The environment variable that sets the level when the operation doesn't
+pub const LOG_ENV: &str = "PREPVIDEO_LOG";
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.with(|l| l.set(level));
+}
+
+pub fn level() -> LogLevel {
+    LEVEL.with(Cell::get)
+}
+
+/// Whether messages of the given level are printed
+pub fn enabled(level: LogLevel) -> bool {
+    level <= self::level()
+}
+
+/// The level named in the environment, if any
+pub fn from_env() -> Result<Option<LogLevel>, Box<dyn std::error::Error>> {
+    match std::env::var(LOG_ENV) {
+        Ok(value) => value.parse::<LogLevel>()
+            .map(Some)
+            .map_err(|_| format!("{} must be Quiet, Normal or Verbose, not {}",
+                                 LOG_ENV, value).into()),
+        Err(_) => Ok(None),
+    }
+}
+
+/// ffmpeg's own -loglevel to match ours, if it should differ from its
+/// default
+pub fn ffmpeg_loglevel() -> Option<&'static str> {
+    match level() {
+        LogLevel::Quiet => Some("error"),
+        LogLevel::Normal => None,
+        LogLevel::Verbose => Some("verbose"),
+    }
+}
@@ -6,15 +6,57 @@ extern crate strum_macros;
 
 use std::io::{Read, Write};
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 
 mod video;
-use video::VCodec;
+use video::{VCodec, KeyframeInterval, EncodeMode, HwAccel, Pass, Deinterlace,
+            DenoiseStrength, FpsMode, Transpose};
 
 mod audio;
 use audio::{ACodec, Loudnorm};
 
+mod tools;
+use tools::{Tools, ToolPaths};
+
+mod input;
+use input::InputClip;
+
+mod probe;
+
+mod subtitle;
+use subtitle::Subtitles;
+mod mapping;
+use mapping::StreamMapping;
+
+mod thumbnail;
+use thumbnail::ThumbnailSpec;
+mod sprites;
+use sprites::SpriteSpec;
+mod watermark;
+use watermark::Watermark;
+mod composite;
+use composite::Composite;
+
+mod defaults;
+use defaults::OperationDefaults;
+
+mod log;
+use log::LogLevel;
+
+mod summary;
+use summary::Summary;
+
+mod run;
+
+mod cleanup;
+use cleanup::Cleanup;
+use run::{run_cmd, run_cmd_with_progress, run_cmd_to_stdout, Progress};
+
 #[derive(Debug, Clone, Copy, PartialEq, Hash)]
 #[derive(Serialize, Deserialize)]
 #[derive(EnumIter, AsRefStr, EnumString)]
@@ -32,7 +74,8 @@ pub enum Quality {
 pub enum Container {
     Mp4,
     Mkv,
-    Webm
+    Webm,
+    Mov,
 }
 impl Container {
     pub fn extension(&self) -> &'static str {
@@ -40,168 +83,2361 @@ impl Container {
             Container::Mp4 => "mp4",
             Container::Mkv => "mkv",
             Container::Webm => "webm",
+            Container::Mov => "mov",
+        }
+    }
+
+    /// ffmpeg's name for it, for when there is no file extension to go by
+    pub fn muxer(&self) -> &'static str {
+        match *self {
+            Container::Mp4 => "mp4",
+            Container::Mkv => "matroska",
+            Container::Webm => "webm",
+            Container::Mov => "mov",
+        }
+    }
+
+    /// The video codec that Auto means: one that most players can play
+    /// from this container.  Mkv is for keeping what the source has.
+    pub fn default_vcodec(&self) -> VCodec {
+        match *self {
+            Container::Mp4 | Container::Mov => VCodec::H264,
+            Container::Mkv => VCodec::Copy,
+            Container::Webm => VCodec::Vp9,
+        }
+    }
+
+    /// The audio codec that Auto means
+    pub fn default_acodec(&self) -> ACodec {
+        match *self {
+            Container::Mp4 | Container::Mov => ACodec::Aac,
+            Container::Mkv => ACodec::Copy,
+            Container::Webm => ACodec::Opus,
+        }
+    }
+
+    /// Whether this container can hold the given video codec.  Copy is
+    /// always allowed since we don't know what the source holds.
+    pub fn supports_vcodec(&self, codec: VCodec) -> bool {
+        match *self {
+            Container::Mkv => true,
+            Container::Mp4 => matches!(codec, VCodec::Copy | VCodec::Av1 | VCodec::SvtAv1
+                                       | VCodec::H264 | VCodec::H265),
+            Container::Webm => matches!(codec, VCodec::Copy | VCodec::Vp8 | VCodec::Vp9
+                                        | VCodec::Av1 | VCodec::SvtAv1),
+            Container::Mov => matches!(codec, VCodec::Copy | VCodec::H264
+                                       | VCodec::H265),
+        }
+    }
+
+    /// Whether this container can hold the given audio codec.  Copy is
+    /// always allowed since we don't know what the source holds.
+    pub fn supports_acodec(&self, codec: ACodec) -> bool {
+        match *self {
+            Container::Mkv => true,
+            Container::Mp4 => matches!(codec, ACodec::Copy | ACodec::Opus
+                                       | ACodec::Aac | ACodec::Mp3),
+            Container::Webm => matches!(codec, ACodec::Copy | ACodec::Opus
+                                        | ACodec::Vorbis),
+            Container::Mov => matches!(codec, ACodec::Copy | ACodec::Aac
+                                       | ACodec::PcmS16),
         }
     }
 }
 
+/// Fields left out are taken from the defaults file if it has them, or
+/// else from `Operation::default()`
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default = "defaults::default_operation", deny_unknown_fields)]
 pub struct Operation {
-    pub cpulimit: u32,
-    pub inputs: Vec<String>,
-    pub transpose: Option<u8>,
-    pub scale: (u16, u16),
+    /// Percentage CPU limit enforced by cpulimit.  If None, ffmpeg is run
+    /// directly without cpulimit.
+    pub cpulimit: Option<u32>,
+    /// The files to encode, joined one after the other.  Each is a path,
+    /// or a path with the part of it to use, e.g.
+    /// `(path: "b.mp4", start: Some(2.0), end: Some(30.0))`.
+    pub inputs: Vec<InputClip>,
+    /// Turn or flip the pixels.  The numbers ffmpeg's transpose filter
+    /// takes (0 to 3) are still accepted.
+    pub transpose: Option<Transpose>,
+    /// Set the rotation (clockwise degrees, a multiple of 90) that players
+    /// display the video with, without touching the pixels, so it works
+    /// with Copy.  0 clears a rotation.  Players apply it after any
+    /// `transpose`, which does rotate the pixels.
+    pub rotate_metadata: Option<i32>,
+    /// Rotate the pixels upright according to the source's rotation
+    /// metadata (as phones set), so that scaling and cropping see the
+    /// picture the way it is displayed.  This happens before `transpose`.
+    pub auto_rotate: bool,
+    /// The (first) input's rotation in clockwise degrees, probed when it
+    /// is to be turned upright (see `rotated_upright`)
+    #[serde(skip)]
+    pub source_rotation: Option<u32>,
+    /// Seconds into the input to start from.  This seeks the input (-ss
+    /// before -i) which is fast.  Since we re-encode, the cut is still
+    /// frame accurate, but with Copy codecs it lands on the keyframe before.
+    pub start: Option<f64>,
+    /// Seconds of the input to encode, from `start`
+    pub duration: Option<f64>,
+    /// Encode only this many seconds (from `start`) as a quick sample of
+    /// the settings.  The output name gets a "_preview" suffix.
+    pub preview_secs: Option<f64>,
+    /// Seconds to fade in from black (and silence) at the start
+    pub fade_in: Option<f64>,
+    /// Seconds to fade out to black (and silence) at the end
+    pub fade_out: Option<f64>,
+    /// Playback speed, e.g. 2.0 for double speed or 0.5 for slow motion
+    pub speed: Option<f64>,
+    /// Deinterlace the source, before anything else is done to the picture
+    pub deinterlace: Option<Deinterlace>,
+    /// Crop to (width, height, x, y) in source pixels, before scaling.
+    /// With a rotated source these are of the picture as displayed.
+    pub crop: Option<(u16, u16, u16, u16)>,
+    /// Remove grain and noise, at some cost in encode time
+    pub denoise: Option<DenoiseStrength>,
+    /// Added to the brightness, from -1.0 to 1.0
+    pub brightness: Option<f32>,
+    /// Contrast multiplier, from 0.0 to 2.0 (1.0 leaves it as it is)
+    pub contrast: Option<f32>,
+    /// Saturation multiplier, from 0.0 (grey) to 3.0
+    pub saturation: Option<f32>,
+    /// Gamma correction, from 0.1 to 10.0.  Above 1.0 lifts the shadows.
+    pub gamma: Option<f32>,
+    /// Output (width, height).  Either may be 0 or -1 meaning "auto", to
+    /// preserve the aspect ratio (rounded to an even number of pixels).
+    /// Odd sizes are rounded up to even ones.
+    pub scale: (i32, i32),
     pub loudnorm: bool,
+    /// Normalize linearly (keeping dynamics).  If false, loudnorm uses
+    /// dynamic normalization.  Defaults to true.
+    pub loudnorm_linear: bool,
+    /// Integrated loudness target in LUFS (-70 to -5).  Defaults to
+    /// LOUDNORM_LUFS.  YouTube wants -14, Apple Podcasts -16, EBU R128 -23.
+    pub loudnorm_i: Option<f32>,
+    /// True peak target in dBTP (-9 to 0).  Defaults to LOUDNORM_TP.
+    pub loudnorm_tp: Option<f32>,
+    /// Loudness range target (1 to 50).  Defaults to LOUDNORM_LRA.
+    pub loudnorm_lra: Option<f32>,
     pub video_quality: Quality,
     pub video_fps: (u32, u32),
+    /// Drop (or duplicate) frames to reach video_fps, or interpolate them
+    pub fps_mode: FpsMode,
+    /// For a variable frame rate source (screen recordings, some phones):
+    /// hold the video to a constant rate by its timestamps as it is
+    /// written, and stretch the audio to its timestamps, so that the two
+    /// do not drift apart.  Such a source is warned about without this.
+    pub vfr_sync: bool,
+    /// Carry the source's colour description, and any HDR mastering
+    /// metadata, through to the output.  Defaults to true.
+    pub keep_color: bool,
+    /// The encoder's pixel format, e.g. "yuv420p10le" to keep 10 bits.
+    /// Defaults to whatever ffmpeg picks for the source.  The bitrate
+    /// scales with its bits per pixel, taking 8-bit 4:2:0 when not given.
+    pub pix_fmt: Option<String>,
     pub video_codec: VCodec,
+    /// Keyframe spacing (GOP size).  Defaults to 240 frames.
+    pub keyframe_interval: Option<KeyframeInterval>,
+    /// Encoder threads.  Defaults to the number of logical CPUs.
+    pub threads: Option<u32>,
+    /// Encode video with hardware instead of software
+    pub hwaccel: Option<HwAccel>,
+    /// A codec to encode with in software instead, if ffmpeg doesn't have
+    /// the encoder or the encode fails (e.g. on a machine without the
+    /// hardware).  The encode is retried once with it.
+    pub fallback_codec: Option<VCodec>,
+    /// Two-pass to a target bitrate, or single-pass constant quality
+    pub encode_mode: EncodeMode,
+    /// Aim for an output of this many megabytes, instead of a bitrate from
+    /// the video quality.  Needs TwoPass.
+    pub target_size_mb: Option<u32>,
+    /// Override the default CRF (constant rate factor) for the video codec
+    pub crf: Option<u8>,
+    /// Override the encoder's speed setting for the first pass, e.g. the
+    /// libvpx -speed or libaom -cpu-used.  Lower is slower and better.
+    pub pass1_speed: Option<u8>,
+    /// Override the encoder's speed setting for the second (or only)
+    /// pass, which otherwise is chosen from the output size.  For SvtAv1
+    /// this is the preset.
+    pub pass2_speed: Option<u8>,
+    /// Lowest bitrate allowed when two-pass, as a percentage of the target.
+    /// Defaults to 50.
+    pub minrate_pct: u32,
+    /// Highest bitrate allowed when two-pass, as a percentage of the target.
+    /// Defaults to 145.  Fast motion may want more headroom.
+    pub maxrate_pct: u32,
     pub audio_quality: Quality,
     pub audio_codec: ACodec,
+    /// Gain (or cut, if negative) in dB, applied before any loudnorm
+    pub volume_db: Option<f32>,
+    /// Milliseconds to shift the audio by, to bring it in sync with the
+    /// picture.  Positive delays it (for audio that comes too early), and
+    /// negative brings it forward (for audio that comes too late).
+    pub audio_offset_ms: Option<i64>,
+    /// Number of output audio channels (1 for mono, 2 for stereo)
+    pub channels: Option<u8>,
+    /// Explicit channel mapping for the pan filter, e.g.
+    /// "stereo|FL=FC+0.30*FL+0.30*BL|FR=FC+0.30*FR+0.30*BR" to downmix 5.1
+    pub pan: Option<String>,
+    /// Which audio stream to use (0 is the first), if not the default
+    pub audio_track: Option<u32>,
+    pub subtitles: Subtitles,
+    /// Which streams to keep.  Defaults to the video, one audio track and
+    /// any copied subtitles.
+    pub stream_mapping: StreamMapping,
     pub strip_metadata: bool,
+    /// Keep chapters (with their titles) from the first input.  This wins
+    /// over strip_metadata for chapter data only.
+    pub keep_chapters: bool,
     pub title: String,
+    /// Keep spaces in the output filename rather than using underscores
+    pub keep_spaces: bool,
+    /// The output filename, without extension, with {title}, {codec},
+    /// {quality}, {width}, {height}, {date} and {container} replaced.
+    /// Defaults to just the title.
+    pub output_template: Option<String>,
     pub container: Container,
+    /// Replace the output file if it already exists
+    pub overwrite: bool,
+    /// Make Mp4 and Mov outputs playable while still downloading.  Defaults
+    /// to true, and does nothing for other containers.
+    pub faststart: bool,
+    /// Size of the (first) input, probed when needed for an auto scale
+    #[serde(skip)]
+    pub source_size: Option<(u32, u32)>,
+    /// The (first) input's colour description, probed for keep_color
+    #[serde(skip)]
+    pub source_color: Option<probe::Color>,
+    /// The inputs have no audio, so every audio setting is ignored
+    #[serde(skip)]
+    pub silent: bool,
+    /// Expected output duration in seconds, needed for target_size_mb
+    #[serde(skip)]
+    pub output_secs: Option<f64>,
+    /// Directory for intermediate files.  Defaults to the current directory.
+    pub work_dir: Option<PathBuf>,
+    /// Directory for the output file.  Defaults to the current directory.
+    pub output_dir: Option<PathBuf>,
+    /// Write the output here, rather than to a file in output_dir named
+    /// from the title.  "-" writes it to stdout (as Mkv or Webm), and then
+    /// everything else is printed to stderr.
+    pub output: Option<String>,
+    /// Also write a poster frame or contact sheet image next to the output
+    pub thumbnail: Option<ThumbnailSpec>,
+    /// Also write seek bar preview sprite sheets and a WebVTT file
+    /// pointing into them, for web players
+    pub sprites: Option<SpriteSpec>,
+    /// An image or text placed over the picture, after scaling
+    pub watermark: Option<Watermark>,
+    /// Output just the audio, in a file of its own type (e.g. .opus or
+    /// .mp3 by the audio_codec).  The container and video settings are
+    /// ignored.
+    pub audio_only: bool,
+    /// Another input shown over or beside the picture.  The bitrate is
+    /// still worked out for the main picture alone.
+    pub composite: Option<Composite>,
+    /// A picture to show for the length of the audio, e.g. to post a
+    /// podcast as a video.  Only the audio is taken from the input.
+    pub still_image: Option<String>,
+    /// Remove intermediate files after a successful run
+    pub cleanup: bool,
+    /// Join the inputs by re-encoding them to a common format, rather than
+    /// by copying streams.  Needed when the inputs differ in codec,
+    /// resolution or timebase.  Trimmed inputs are always joined this way.
+    pub concat_reencode: bool,
+    pub tools: Tools,
+    /// Print the commands that would be run without running them
+    pub dry_run: bool,
+    /// Arguments passed to ffmpeg as they are (without any escaping) after
+    /// all of ours, just before the output filename.  For options this
+    /// tool doesn't have.
+    pub extra_args: Vec<String>,
+    /// Kill any one command that runs for longer than this many seconds
+    pub timeout_secs: Option<u64>,
+    /// Finish by printing a summary of the result as a line of JSON.  Also
+    /// set by the --json flag.  Everything else is then printed to stderr,
+    /// so stdout carries only the JSON.
+    pub json_output: bool,
+    /// How much to print.  Defaults to $PREPVIDEO_LOG, or else Normal.
+    pub log_level: Option<LogLevel>,
+}
+
+impl Default for Operation {
+    fn default() -> Operation {
+        Operation {
+            cpulimit: None,
+            inputs: Vec::new(),
+            transpose: None,
+            rotate_metadata: None,
+            auto_rotate: false,
+            source_rotation: None,
+            start: None,
+            duration: None,
+            preview_secs: None,
+            fade_in: None,
+            fade_out: None,
+            speed: None,
+            deinterlace: None,
+            crop: None,
+            denoise: None,
+            brightness: None,
+            contrast: None,
+            saturation: None,
+            gamma: None,
+            scale: (1280, -1),
+            loudnorm: true,
+            loudnorm_linear: true,
+            loudnorm_i: None,
+            loudnorm_tp: None,
+            loudnorm_lra: None,
+            video_quality: Quality::Medium,
+            video_fps: (30000, 1001),
+            fps_mode: FpsMode::Drop,
+            vfr_sync: false,
+            keep_color: true,
+            pix_fmt: None,
+            video_codec: VCodec::Auto,
+            keyframe_interval: None,
+            threads: None,
+            hwaccel: None,
+            fallback_codec: None,
+            encode_mode: EncodeMode::TwoPass,
+            target_size_mb: None,
+            crf: None,
+            pass1_speed: None,
+            pass2_speed: None,
+            minrate_pct: 50,
+            maxrate_pct: 145,
+            audio_quality: Quality::Medium,
+            audio_codec: ACodec::Auto,
+            volume_db: None,
+            audio_offset_ms: None,
+            channels: None,
+            pan: None,
+            audio_track: None,
+            subtitles: Subtitles::None,
+            stream_mapping: StreamMapping::DefaultOnly,
+            strip_metadata: true,
+            keep_chapters: false,
+            title: String::new(),
+            keep_spaces: false,
+            output_template: None,
+            container: Container::Webm,
+            overwrite: false,
+            faststart: true,
+            source_size: None,
+            source_color: None,
+            silent: false,
+            output_secs: None,
+            work_dir: None,
+            output_dir: None,
+            output: None,
+            thumbnail: None,
+            sprites: None,
+            watermark: None,
+            audio_only: false,
+            composite: None,
+            still_image: None,
+            cleanup: true,
+            concat_reencode: false,
+            tools: Tools::default(),
+            dry_run: false,
+            extra_args: Vec::new(),
+            timeout_secs: None,
+            json_output: false,
+            log_level: None,
+        }
+    }
+}
+
+impl Operation {
+    /// Check the operation for problems before we spend any time encoding
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.title.is_empty() {
+            return Err("No title was given".into());
+        }
+        if let Some(ref template) = self.output_template {
+            if template.contains(['/', '\\']) {
+                return Err("output_template cannot contain a path.  Use output_dir.".into());
+            }
+            output_stem(self)?;
+        }
+        if self.audio_only {
+            if self.watermark.is_some() || self.composite.is_some() || self.thumbnail.is_some()
+                || self.sprites.is_some() || self.subtitles != Subtitles::None
+            {
+                return Err("audio_only cannot have a watermark, composite, thumbnail, \
+                            sprites or subtitles".into());
+            }
+            if self.target_size_mb.is_some() {
+                return Err("target_size_mb is for video, not audio_only".into());
+            }
+        }
+        if self.still_image.is_some() {
+            if self.audio_only || self.video_codec == VCodec::Copy {
+                return Err("still_image requires the video to be encoded".into());
+            }
+            if self.inputs.len() > 1 {
+                return Err("still_image takes a single audio input".into());
+            }
+            if self.composite.is_some()
+                || self.watermark.as_ref().is_some_and(|w| w.image().is_some())
+                || self.stream_mapping != StreamMapping::DefaultOnly
+            {
+                return Err("still_image cannot be used with a composite, image \
+                            watermark or stream_mapping".into());
+            }
+        }
+        if self.stream_mapping != StreamMapping::DefaultOnly {
+            if self.stream_mapping == StreamMapping::KeepAll && self.container != Container::Mkv {
+                return Err("stream_mapping KeepAll needs Mkv, which can hold any \
+                            kind of stream".into());
+            }
+            if self.stream_mapping == StreamMapping::Explicit(Vec::new()) {
+                return Err("stream_mapping Explicit needs at least one stream".into());
+            }
+            if self.audio_track.is_some() {
+                return Err("audio_track cannot be used with stream_mapping, which \
+                            chooses the tracks itself".into());
+            }
+            if self.composite.is_some()
+                || self.watermark.as_ref().is_some_and(|w| w.image().is_some())
+            {
+                return Err("stream_mapping cannot be used with a composite or image \
+                            watermark, whose video is made by a filtergraph".into());
+            }
+            if self.reencodes_concat() {
+                return Err("stream_mapping cannot be used when the inputs are joined \
+                            by re-encoding, which keeps only video and audio".into());
+            }
+            if self.loudnorm {
+                println!("WARNING: loudnorm measures the first audio track, but is \
+                          applied to every one kept");
+            }
+        }
+        if !self.audio_only && !self.container.supports_vcodec(self.video_codec) {
+            return Err(format!("Container {} cannot hold {} video",
+                               self.container.as_ref(),
+                               self.video_codec.as_ref()).into());
+        }
+        if !self.audio_only && !self.container.supports_acodec(self.audio_codec) {
+            return Err(format!("Container {} cannot hold {} audio",
+                               self.container.as_ref(),
+                               self.audio_codec.as_ref()).into());
+        }
+        if let Some(hwaccel) = self.hwaccel {
+            if self.video_codec != VCodec::Copy
+                && hwaccel.encoder(self.video_codec).is_none()
+            {
+                return Err(format!("{} cannot encode {}", hwaccel.as_ref(),
+                                   self.video_codec.as_ref()).into());
+            }
+        }
+        if let Some(ref pix_fmt) = self.pix_fmt {
+            if self.video_codec == VCodec::Copy || self.hwaccel.is_some() {
+                return Err("pix_fmt requires the video to be re-encoded in software".into());
+            }
+            if !self.video_codec.pix_fmts().contains(&pix_fmt.as_str()) {
+                return Err(format!("{} cannot encode pix_fmt {} (only {})",
+                                   self.video_codec.as_ref(), pix_fmt,
+                                   self.video_codec.pix_fmts().join(", ")).into());
+            }
+        }
+        if let Some(fallback) = self.fallback_codec {
+            if matches!(fallback, VCodec::Copy | VCodec::Auto) {
+                return Err("fallback_codec must be a codec to encode with".into());
+            }
+            let mut fallen_back = self.clone();
+            fallen_back.use_fallback();
+            fallen_back.validate()
+                .map_err(|e| format!("With the fallback_codec: {}", e))?;
+        }
+        if let Some(crf) = self.crf {
+            let max = self.video_codec.max_crf();
+            if crf > max {
+                return Err(format!("crf {} is out of range for {} (0-{})",
+                                   crf, self.video_codec.as_ref(), max).into());
+            }
+            if matches!(self.video_codec, VCodec::SvtAv1 | VCodec::H264 | VCodec::H265)
+                && self.encode_mode == EncodeMode::TwoPass
+            {
+                println!("WARNING: {} ignores the bitrate when given a crf, so the crf \
+                          is only used in ConstantQuality", self.video_codec.as_ref());
+            }
+        }
+        if self.vfr_sync && self.video_codec == VCodec::Copy && !self.audio_only {
+            return Err("vfr_sync requires the video to be re-encoded".into());
+        }
+        if self.pass1_speed.is_some() || self.pass2_speed.is_some() {
+            let (min, max) = self.video_codec.speed_range().ok_or_else(
+                || format!("{} has no numbered speed setting, only a preset from the \
+                            video_quality", self.video_codec.as_ref()))?;
+            for speed in self.pass1_speed.iter().chain(&self.pass2_speed) {
+                if !(min..=max).contains(speed) {
+                    return Err(format!("Speed {} is out of range for {} ({}-{})",
+                                       speed, self.video_codec.as_ref(), min, max).into());
+                }
+            }
+            if self.hwaccel.is_some() {
+                println!("WARNING: pass1_speed and pass2_speed are only used by the \
+                          software encoders");
+            }
+        }
+        if let Some(mb) = self.target_size_mb {
+            if mb == 0 {
+                return Err("target_size_mb must be positive".into());
+            }
+            if self.video_codec == VCodec::Copy || self.encode_mode != EncodeMode::TwoPass {
+                return Err("target_size_mb needs the video re-encoded with TwoPass".into());
+            }
+            if self.audio_codec == ACodec::Copy {
+                println!("WARNING: The audio is copied, so its size cannot be allowed \
+                          for in target_size_mb");
+            }
+        }
+        if self.minrate_pct > 100 {
+            return Err(format!("minrate_pct {} is over 100", self.minrate_pct).into());
+        }
+        if self.maxrate_pct < 100 {
+            return Err(format!("maxrate_pct {} is under 100", self.maxrate_pct).into());
+        }
+        if self.scale.0 < -1 || self.scale.1 < -1 {
+            return Err("scale dimensions must be positive, or 0 or -1 for auto".into());
+        }
+        if self.scale.0 <= 0 && self.scale.1 <= 0 {
+            return Err("At most one scale dimension can be auto".into());
+        }
+        if !self.subtitles.supported_by(self.container) {
+            return Err(format!("Container {} cannot hold the subtitles",
+                               self.container.as_ref()).into());
+        }
+        if self.loudnorm_i.is_some_and(|i| !(-70.0..=-5.0).contains(&i)) {
+            return Err("loudnorm_i must be between -70 and -5".into());
+        }
+        if self.loudnorm_tp.is_some_and(|tp| !(-9.0..=0.0).contains(&tp)) {
+            return Err("loudnorm_tp must be between -9 and 0".into());
+        }
+        if self.loudnorm_lra.is_some_and(|lra| !(1.0..=50.0).contains(&lra)) {
+            return Err("loudnorm_lra must be between 1 and 50".into());
+        }
+        if self.channels.is_some_and(|c| c == 0 || c > 8) {
+            return Err("channels must be between 1 and 8".into());
+        }
+        if self.thumbnail.is_some_and(|t| t.count() == 0 || t.width == 0) {
+            return Err("thumbnail columns, rows and width must be positive".into());
+        }
+        if self.sprites.is_some_and(|s| s.interval <= 0.0 || s.columns == 0 || s.rows == 0
+                                    || s.width == 0)
+        {
+            return Err("sprites interval, columns, rows and width must be positive".into());
+        }
+        if self.watermark.is_some() && self.video_codec == VCodec::Copy {
+            return Err("A watermark requires the video to be re-encoded".into());
+        }
+        if matches!(self.subtitles, Subtitles::BurnIn(_)) && self.video_codec == VCodec::Copy {
+            return Err("Burning in subtitles requires the video to be re-encoded".into());
+        }
+        if let Some(Watermark::Text { ref text, .. }) = self.watermark {
+            if text.is_empty() {
+                return Err("watermark text cannot be empty".into());
+            }
+        }
+        if self.composite.is_some() && self.video_codec == VCodec::Copy {
+            return Err("A composite requires the video to be re-encoded".into());
+        }
+        if let Some(Composite::PictureInPicture { width, .. }) = self.composite {
+            if width < 2 {
+                return Err("The picture in picture width must be at least 2".into());
+            }
+        }
+        if self.rotate_metadata.is_some_and(|degrees| degrees % 90 != 0) {
+            return Err("rotate_metadata must be a multiple of 90 degrees".into());
+        }
+        if self.deinterlace.is_some() && self.video_codec == VCodec::Copy {
+            return Err("Deinterlacing requires the video to be re-encoded".into());
+        }
+        if self.denoise.is_some() && self.video_codec == VCodec::Copy {
+            return Err("Denoising requires the video to be re-encoded".into());
+        }
+        if let Some((w, h, _, _)) = self.crop {
+            if w == 0 || h == 0 {
+                return Err("crop width and height must be positive".into());
+            }
+            if self.video_codec == VCodec::Copy {
+                return Err("Cropping requires the video to be re-encoded".into());
+            }
+        }
+        let ranges = [("brightness", self.brightness, -1.0, 1.0),
+                      ("contrast", self.contrast, 0.0, 2.0),
+                      ("saturation", self.saturation, 0.0, 3.0),
+                      ("gamma", self.gamma, 0.1, 10.0)];
+        for (name, value, min, max) in ranges {
+            if let Some(value) = value {
+                if !(min..=max).contains(&value) {
+                    return Err(format!("{} must be from {} to {}", name, min, max).into());
+                }
+                if self.video_codec == VCodec::Copy {
+                    return Err(format!("Changing {} requires the video to be re-encoded",
+                                       name).into());
+                }
+            }
+        }
+        if (self.fade_in.is_some() || self.fade_out.is_some())
+            && self.video_codec == VCodec::Copy && !self.audio_only
+        {
+            return Err("Fading requires the video to be re-encoded".into());
+        }
+        if self.audio_offset_ms.is_some() && self.audio_codec == ACodec::Copy {
+            return Err("audio_offset_ms requires the audio to be re-encoded".into());
+        }
+        if let Some(speed) = self.speed {
+            if speed <= 0.0 {
+                return Err("speed must be positive".into());
+            }
+            if (self.video_codec == VCodec::Copy && !self.audio_only)
+                || self.audio_codec == ACodec::Copy
+            {
+                return Err("Changing speed requires the video and audio to be re-encoded".into());
+            }
+        }
+        if self.fade_in.is_some_and(|d| d <= 0.0) || self.fade_out.is_some_and(|d| d <= 0.0) {
+            return Err("fade_in and fade_out must be positive".into());
+        }
+        if let Some(duration) = self.duration {
+            if self.fade_in.unwrap_or(0.0) + self.fade_out.unwrap_or(0.0) > duration {
+                println!("WARNING: The fades are longer than the duration, so will overlap");
+            }
+        }
+        if cfg!(windows) && self.cpulimit.is_some() {
+            return Err("cpulimit is not available on Windows".into());
+        }
+        if cfg!(windows) && self.hwaccel == Some(HwAccel::Vaapi) {
+            return Err("Vaapi is not available on Windows".into());
+        }
+        if self.timeout_secs == Some(0) {
+            return Err("timeout_secs must be positive".into());
+        }
+        if self.start.is_some_and(|s| s < 0.0) {
+            return Err("start cannot be negative".into());
+        }
+        if self.duration.is_some_and(|d| d <= 0.0) {
+            return Err("duration must be positive".into());
+        }
+        if self.preview_secs.is_some_and(|d| d <= 0.0) {
+            return Err("preview_secs must be positive".into());
+        }
+        for clip in &self.inputs {
+            clip.validate()?;
+        }
+        if self.inputs.len() == 1 && self.inputs[0].is_trimmed() {
+            return Err("A single input is trimmed with the operation's start and \
+                        duration, rather than its own".into());
+        }
+        if self.to_stdout() {
+            if !matches!(self.container, Container::Mkv | Container::Webm) || self.audio_only {
+                return Err("Only Mkv and Webm can be written to stdout, since the \
+                            others have to seek back to finish the file".into());
+            }
+            if self.thumbnail.is_some() || self.sprites.is_some() || self.json_output {
+                return Err("thumbnail, sprites and json_output need an output file, \
+                            not stdout".into());
+            }
+        }
+        if self.container == Container::Mp4 && self.audio_codec == ACodec::Opus
+            && !self.audio_only
+        {
+            println!("WARNING: Opus audio in Mp4 is poorly supported by many players. \
+                      Consider Aac instead.");
+        }
+        Ok(())
+    }
+
+    /// Whether the inputs are joined by re-encoding (which is only needed
+    /// when there is more than one).  Trimmed inputs can't be joined by
+    /// copying, which could only cut them at keyframes.
+    pub fn reencodes_concat(&self) -> bool {
+        self.inputs.len() > 1
+            && (self.concat_reencode || self.inputs.iter().any(InputClip::is_trimmed))
+    }
+
+    /// The audio track to select from the source that is encoded.  If the
+    /// concat was re-encoded, the track was already selected there.
+    pub fn source_audio_track(&self) -> Option<u32> {
+        if self.reencodes_concat() {
+            None
+        } else {
+            self.audio_track
+        }
+    }
+
+    /// The output file extension
+    pub fn extension(&self) -> &'static str {
+        if self.audio_only {
+            self.audio_codec.extension()
+        } else {
+            self.container.extension()
+        }
+    }
+
+    pub fn to_stdout(&self) -> bool {
+        self.output.as_deref() == Some("-")
+    }
+
+    /// Seconds of the input to use: the duration, cut short for a preview
+    pub fn input_duration(&self) -> Option<f64> {
+        match (self.duration, self.preview_secs) {
+            (Some(duration), Some(preview)) => Some(duration.min(preview)),
+            (duration, preview) => duration.or(preview),
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
+    }
+
+    /// Round odd scale dimensions up to even ones, as yuv420p (and so most
+    /// encoders) cannot have odd sizes
+    pub fn round_scale_to_even(&mut self) {
+        let (w, h) = self.scale;
+        let even = |n: i32| if n > 0 && n % 2 == 1 { n + 1 } else { n };
+        if (even(w), even(h)) != (w, h) {
+            println!("WARNING: scale {}x{} has an odd dimension, using {}x{} instead",
+                     w, h, even(w), even(h));
+            self.scale = (even(w), even(h));
+        }
+    }
+
+    /// Whether a scale dimension is left for ffmpeg to work out
+    pub fn scale_is_auto(&self) -> bool {
+        self.scale.0 <= 0 || self.scale.1 <= 0
+    }
+
+    /// The expected output (width, height).  An auto dimension is computed
+    /// from the source aspect ratio (after crop and transpose), which is
+    /// assumed to be 16:9 if it could not be probed.
+    pub fn output_size(&self) -> (u32, u32) {
+        let (sw, sh) = match (self.crop, self.source_size) {
+            (Some((w, h, _, _)), _) => (w as u32, h as u32),
+            (None, Some(size)) => size,
+            (None, None) => (16, 9),
+        };
+        // (a crop is already of the upright picture)
+        let quarter_turned = self.crop.is_none() && self.rotated_upright()
+            && matches!(self.source_rotation, Some(90) | Some(270));
+        let transposed = self.transpose.is_some_and(|t| t.turns_quarter());
+        let (sw, sh) = if transposed != quarter_turned {
+            (sh, sw)
+        } else {
+            (sw, sh)
+        };
+        let even = |n: u32| (n.div_ceil(2) * 2).max(2);
+        match self.scale {
+            (w, h) if w <= 0 => (even(h as u32 * sw / sh), h as u32),
+            (w, h) if h <= 0 => (w as u32, even(w as u32 * sh / sw)),
+            (w, h) => (w as u32, h as u32),
+        }
+    }
+
+    /// Check that the crop rectangle fits within each input (or the
+    /// still_image), where the input size can be determined
+    pub fn check_crop(&self, tools: &ToolPaths) -> Result<(), Box<dyn std::error::Error>> {
+        let (w, h, x, y) = match self.crop {
+            Some(crop) => crop,
+            None => return Ok(()),
+        };
+        let pictures: Vec<&String> = match self.still_image {
+            Some(ref image) => vec![image],
+            None => self.inputs.iter().map(|clip| &clip.path).collect(),
+        };
+        for input in pictures {
+            match probe::video_size(tools, input) {
+                Ok((width, height)) => {
+                    let (width, height) = if self.displays_turned(tools, input) {
+                        (height, width)
+                    } else {
+                        (width, height)
+                    };
+                    if x as u32 + w as u32 > width || y as u32 + h as u32 > height {
+                        return Err(format!("Crop {}x{}+{}+{} exceeds the {}x{} size of {}",
+                                           w, h, x, y, width, height, input).into());
+                    }
+                },
+                Err(e) => println!("WARNING: Could not check crop against {}: {}",
+                                   input, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a rotated source is turned upright: by auto_rotate, or else
+    /// by ffmpeg itself when rotate_metadata doesn't stop it.  We then do
+    /// the turning, so that the size it comes out at is known.
+    pub fn rotated_upright(&self) -> bool {
+        self.auto_rotate || self.rotate_metadata.is_none()
+    }
+
+    /// Whether the crop of an input is of its picture turned a quarter from
+    /// how it is stored
+    fn displays_turned(&self, tools: &ToolPaths, input: &str) -> bool {
+        self.rotated_upright()
+            && matches!(probe::rotation(tools, input), Ok(90) | Ok(270))
+    }
+
+    /// Check that the chosen audio track exists in each input, where the
+    /// streams can be probed
+    pub fn check_audio_track(&self, tools: &ToolPaths) -> Result<(), Box<dyn std::error::Error>> {
+        let track = match self.audio_track {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+        for input in self.inputs.iter().map(|clip| &clip.path) {
+            match probe::audio_stream_count(tools, input) {
+                Ok(count) if track >= count => {
+                    return Err(format!("Audio track {} does not exist in {}, which has {}",
+                                       track, input, count).into());
+                },
+                Ok(_) => { },
+                Err(e) => println!("WARNING: Could not check audio tracks of {}: {}",
+                                   input, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace Auto codecs with the container's defaults.  This is done
+    /// before anything else looks at the codecs.
+    pub fn resolve_auto_codecs(&mut self) {
+        if self.video_codec == VCodec::Auto {
+            self.video_codec = self.container.default_vcodec();
+        }
+        if self.audio_codec == ACodec::Auto {
+            self.audio_codec = self.container.default_acodec();
+        }
+    }
+
+    /// Switch to the fallback codec, in software, returning it.  It is used
+    /// up, so this happens at most once.
+    pub fn use_fallback(&mut self) -> Option<VCodec> {
+        let fallback = self.fallback_codec.take()?;
+        self.video_codec = fallback;
+        self.hwaccel = None;
+        Some(fallback)
+    }
+
+    /// Check that ffmpeg has the encoders this needs, rather than failing
+    /// part way through
+    pub fn check_encoders(&self, tools: &ToolPaths) -> Result<(), Box<dyn std::error::Error>> {
+        let available = match tools.encoders() {
+            Ok(encoders) => encoders,
+            Err(e) => {
+                println!("WARNING: Could not check ffmpeg's encoders: {}", e);
+                return Ok(());
+            },
+        };
+        let mut needed: Vec<&str> = Vec::new();
+        if !self.audio_only {
+            needed.extend(match self.hwaccel {
+                Some(hwaccel) => hwaccel.encoder(self.video_codec),
+                None => self.video_codec.encoder(),
+            });
+        }
+        needed.extend(self.audio_codec.encoder());
+        if self.reencodes_concat() {
+            needed.extend(["libx264", "aac"]);
+        }
+        for encoder in needed {
+            if !available.contains(encoder) {
+                return Err(format!("{} does not have the {} encoder.  Use an ffmpeg \
+                                    built with it, or another codec.",
+                                   tools.ffmpeg.display(), encoder).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether none of the inputs has audio.  A mix of inputs with and
+    /// without audio cannot be concatenated.
+    pub fn probe_silent(&self, tools: &ToolPaths) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut silent = Vec::new();
+        for input in self.inputs.iter().map(|clip| &clip.path) {
+            match probe::audio_stream_count(tools, input) {
+                Ok(count) => silent.push(count == 0),
+                Err(e) => {
+                    println!("WARNING: Could not check for audio in {}: {}", input, e);
+                    return Ok(false);
+                },
+            }
+        }
+        if silent.iter().all(|s| *s) {
+            return Ok(true);
+        }
+        if let Some(i) = silent.iter().position(|s| *s) {
+            return Err(format!("Input {} has no audio, so it cannot be joined with \
+                                inputs that do", self.inputs[i].path).into());
+        }
+        Ok(false)
+    }
+
+    /// Check that there are inputs, and that each of them can be read
+    pub fn check_inputs(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.inputs.is_empty() {
+            return Err("No inputs were given".into());
+        }
+        for input in self.inputs.iter().map(|clip| &clip.path) {
+            if let Err(e) = File::open(input) {
+                return Err(format!("Cannot read input {}: {}", input, e).into());
+            }
+        }
+        if let Subtitles::BurnIn(ref path) = self.subtitles {
+            if let Err(e) = File::open(path) {
+                return Err(format!("Cannot read subtitles {}: {}", path, e).into());
+            }
+        }
+        if let Some(image) = self.watermark.as_ref().and_then(|w| w.image()) {
+            if let Err(e) = File::open(image) {
+                return Err(format!("Cannot read watermark {}: {}", image, e).into());
+            }
+        }
+        if let Some(ref composite) = self.composite {
+            if let Err(e) = File::open(composite.input()) {
+                return Err(format!("Cannot read composite input {}: {}",
+                                   composite.input(), e).into());
+            }
+        }
+        if let Some(ref image) = self.still_image {
+            if let Err(e) = File::open(image) {
+                return Err(format!("Cannot read still_image {}: {}", image, e).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Several operations, run one after another
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Batch {
+    pub operations: Vec<Operation>,
+    /// How many operations to run at once.  Each operation's cpulimit is
+    /// then shared among those running at once.  Defaults to 1.
+    #[serde(default = "one")]
+    pub parallelism: usize,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// How the operations are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ron,
+    /// An operation object, or an array of them
+    Json,
 }
 
-const CPULIMIT_PATH: &'static str = "/usr/bin/cpulimit";
-const FFMPEG_PATH: &'static str = "/usr/bin/ffmpeg";
+impl Format {
+    fn from_name(name: &str) -> Result<Format, Box<dyn std::error::Error>> {
+        match name {
+            "ron" => Ok(Format::Ron),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("--format must be ron or json, not {}", name).into()),
+        }
+    }
+
+    /// From the file extension, for files ending in .json
+    fn from_path(path: &str) -> Format {
+        match Path::new(path).extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Format::Json,
+            _ => Format::Ron,
+        }
+    }
+}
+
+const USAGE: &str = "Usage: prepvideo [--json] [--check] [--template] [--version] \
+                     [--format ron|json] [--parallel N] [--config PATH] [OPERATION_FILE]";
 
 fn main() -> Result<(), Box<dyn std::error::Error>>
 {
-    println!("Reading operation from stdin...");
-    // Read operation from input
+    let env_level = log::from_env()?;
+    log::set_level(env_level.unwrap_or_default());
+
+    let mut json_flag = false;
+    let mut config_path: Option<PathBuf> = None;
+    let mut operation_path: Option<String> = None;
+    let mut format: Option<Format> = None;
+    let mut template = false;
+    let mut version = false;
+    let mut check = false;
+    let mut parallelism: Option<usize> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--json" => json_flag = true,
+            "--template" => template = true,
+            "--version" => version = true,
+            "--check" => check = true,
+            "--format" => format = Some(Format::from_name(
+                &args.next().ok_or("--format needs ron or json")?)?),
+            "--parallel" => parallelism = Some(
+                args.next().and_then(|n| n.parse().ok()).filter(|n| *n > 0)
+                    .ok_or("--parallel needs a number of operations")?),
+            "--config" => config_path = Some(args.next()
+                                             .ok_or("--config needs a path")?.into()),
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option {}.  {}", arg, USAGE).into());
+            },
+            _ if operation_path.is_some() => {
+                return Err(format!("Only one operation file can be given.  {}", USAGE).into());
+            },
+            _ => operation_path = Some(arg),
+        }
+    }
+
+    // Defaults for fields that operations leave out.  A file given with
+    // --config must exist, but the usual one is optional.
+    match (config_path, OperationDefaults::default_path()) {
+        (Some(path), _) => defaults::set(OperationDefaults::load(&path)?),
+        (None, Some(path)) if path.is_file() => defaults::set(OperationDefaults::load(&path)?),
+        _ => { },
+    }
+
+    if version {
+        return print_version();
+    }
+
+    // An operation to start from, with every field filled in
+    if template {
+        let mut operation = defaults::default_operation();
+        operation.inputs = vec![InputClip::from("input.mp4")];
+        operation.title = "My Video".to_string();
+        let pretty = ron::ser::PrettyConfig::new();
+        println!("{}", ron::ser::to_string_pretty(&operation, pretty)?);
+        return Ok(());
+    }
+
+    // Read operation from the file named on the command line, or stdin
     let mut buffer = String::new();
-    std::io::stdin().read_to_string(&mut buffer)?;
+    let format = format.unwrap_or_else(|| operation_path.as_deref()
+                                       .map(Format::from_path)
+                                       .unwrap_or(Format::Ron));
+    // (to stderr, since the operation may be writing its output to stdout)
+    match operation_path {
+        Some(path) => {
+            if log::enabled(LogLevel::Normal) {
+                eprintln!("Reading operation from {}...", path);
+            }
+            File::open(&path)?.read_to_string(&mut buffer)?;
+        },
+        None => {
+            if log::enabled(LogLevel::Normal) {
+                eprintln!("Reading operation from stdin...");
+            }
+            std::io::stdin().read_to_string(&mut buffer)?;
+        }
+    }
+
+    let batch = match format {
+        Format::Ron => parse_operations(&buffer)?,
+        Format::Json => Batch { operations: parse_json_operations(&buffer)?, parallelism: 1 },
+    };
+    let mut operations = batch.operations;
+    if check {
+        return check_operations(operations);
+    }
+    if operations.iter().any(Operation::to_stdout) {
+        if operations.len() > 1 {
+            return Err("Only a single operation can write its output to stdout".into());
+        }
+        run::reserve_stdout()?;
+    } else if json_flag || operations.iter().any(|o| o.json_output) {
+        // So that stdout carries only the JSON
+        if let Err(e) = run::reserve_stdout() {
+            println!("WARNING: The JSON summary will be mixed in with everything else \
+                      printed: {}", e);
+        }
+    }
+    run::handle_interrupts();
+    if operations.len() == 1 {
+        let operation = operations.remove(0);
+        return run_operation(operation, json_flag, env_level, None);
+    }
+
+    let count = operations.len();
+    let parallelism = parallelism.unwrap_or(batch.parallelism).clamp(1, count);
+    if parallelism > 1 {
+        for operation in &mut operations {
+            operation.cpulimit = operation.cpulimit
+                .map(|limit| (limit / parallelism as u32).max(1));
+        }
+    }
+
+    // Keep going past failures, and report on them all at the end.  Each
+    // worker takes the next operation when it finishes one.
+    let queue = Mutex::new(operations.into_iter().enumerate());
+    let failures: Mutex<Vec<(usize, String, String)>> = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..parallelism {
+            scope.spawn(|| loop {
+                if run::interrupted() {
+                    break;
+                }
+                let next = queue.lock().unwrap().next();
+                let (i, operation) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let title = operation.title.clone();
+                if let Err(e) = run_operation(operation, json_flag, env_level, Some(i + 1)) {
+                    println!("ERROR: Operation {} ({}) failed: {}", i + 1, title, e);
+                    failures.lock().unwrap().push((i + 1, title, e.to_string()));
+                }
+            });
+        }
+    });
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort();
+    // (left in the queue when interrupted)
+    let not_run = queue.into_inner().unwrap().count();
+    println!("Batch finished: {} succeeded, {} failed", count - failures.len() - not_run,
+             failures.len());
+    for (n, title, error) in &failures {
+        println!("  FAILED {} ({}): {}", n, title,
+                 error.lines().next().unwrap_or(""));
+    }
+    if not_run > 0 {
+        return Err(format!("Interrupted, with {} of {} operations not run",
+                           not_run, count).into());
+    }
+    if !failures.is_empty() {
+        return Err(format!("{} of {} operations failed", failures.len(), count).into());
+    }
+    Ok(())
+}
 
-    // Deserialize as ron
-    let operation: Operation = ron::de::from_str(&buffer)?;
+/// Validate the operations and check that their files can be read, without
+/// running anything, reporting on each
+fn check_operations(operations: Vec<Operation>) -> Result<(), Box<dyn std::error::Error>> {
+    let count = operations.len();
+    let mut failed = 0;
+    for (i, mut operation) in operations.into_iter().enumerate() {
+        operation.resolve_auto_codecs();
+        let checked = operation.validate()
+            .and_then(|_| operation.check_inputs());
+        match checked {
+            Ok(()) => {
+                // (which warns of odd sizes)
+                operation.round_scale_to_even();
+                println!("OK: Operation {} ({})", i + 1, operation.title);
+            },
+            Err(e) => {
+                println!("ERROR: Operation {} ({}): {}", i + 1, operation.title, e);
+                failed += 1;
+            },
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} operations failed the check", failed, count).into());
+    }
+    Ok(())
+}
 
-    println!("Operation is: {:?}", operation);
+/// The document is a single Operation, a list of them, or a Batch
+fn parse_operations(document: &str) -> Result<Batch, Box<dyn std::error::Error>> {
+    let document = &upgrade_transpose(document);
+    let first_line = document.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with("//"))
+        .unwrap_or("");
+    let batch = if first_line.starts_with('[') {
+        Batch { operations: ron::de::from_str(document)?, parallelism: 1 }
+    } else if first_line.starts_with("Batch") {
+        ron::de::from_str::<Batch>(document)?
+    } else {
+        Batch { operations: vec![ron::de::from_str(document)?], parallelism: 1 }
+    };
+    if batch.operations.is_empty() {
+        return Err("No operations were given".into());
+    }
+    Ok(batch)
+}
+
+/// Write transposes given as numbers, e.g. `transpose: Some(1)`, by name.
+/// RON can't read a value that may be either a name or a number, so this
+/// has to be done to the text.  Only a field named transpose is changed,
+/// not the word within a string or comment.
+fn upgrade_transpose(document: &str) -> String {
+    let bytes = document.as_bytes();
+    let ident = |c: u8| c.is_ascii_alphanumeric() || c == b'_';
+    let mut upgraded = String::with_capacity(document.len());
+    let mut copied = 0;
+    // The last byte of the last token, since a field name follows ( or ,
+    let mut previous = b'(';
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &document[i..];
+        if bytes[i] == b'"' {
+            i = string_end(bytes, i + 1, None);
+            previous = b'"';
+        } else if bytes[i] == b'r' && (i == 0 || !ident(bytes[i - 1]))
+            && rest[1..].trim_start_matches('#').starts_with('"')
+        {
+            let hashes = rest[1..].len() - rest[1..].trim_start_matches('#').len();
+            i = string_end(bytes, i + 2 + hashes, Some(hashes));
+            previous = b'"';
+        } else if rest.starts_with("//") {
+            i += rest.find('\n').unwrap_or(rest.len());
+        } else if rest.starts_with("/*") {
+            i += rest.find("*/").map(|end| end + 2).unwrap_or(rest.len());
+        } else if bytes[i].is_ascii_whitespace() {
+            i += 1;
+        } else if rest.starts_with("transpose") && matches!(previous, b'(' | b',')
+            && !rest[9..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        {
+            i += 9;
+            previous = b'e';
+            // Looking for : Some ( N )
+            let value = rest[9..].trim_start().strip_prefix(':')
+                .map(str::trim_start)
+                .and_then(|s| s.strip_prefix("Some"))
+                .map(str::trim_start)
+                .and_then(|s| s.strip_prefix('('))
+                .map(str::trim_start);
+            if let Some(value) = value {
+                let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+                let closed = value[digits..].trim_start().starts_with(')');
+                let transpose = value[..digits].parse().ok().and_then(Transpose::from_code);
+                if let (Some(transpose), true) = (transpose, closed) {
+                    let at = document.len() - value.len();
+                    upgraded.push_str(&document[copied..at]);
+                    upgraded.push_str(transpose.as_ref());
+                    copied = at + digits;
+                    i = copied;
+                }
+            }
+        } else {
+            previous = bytes[i];
+            i += 1;
+        }
+    }
+    upgraded.push_str(&document[copied..]);
+    upgraded
+}
+
+/// Where a string ends, given where it starts (just after its opening
+/// quote).  A raw string is closed by a quote and its number of hashes,
+/// while in any other a quote can be escaped.
+fn string_end(bytes: &[u8], start: usize, raw_hashes: Option<usize>) -> usize {
+    let hashes = raw_hashes.unwrap_or(0);
+    let mut i = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if raw_hashes.is_none() => i += 2,
+            b'"' if bytes[i + 1..].iter().take_while(|&&c| c == b'#').count() >= hashes => {
+                return i + 1 + hashes;
+            },
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Our version and that of the ffmpeg that would be run, for bug reports.
+/// This uses the tools from the defaults.
+fn print_version() -> Result<(), Box<dyn std::error::Error>> {
+    println!("prepvideo {}", env!("CARGO_PKG_VERSION"));
+    let tools = defaults::default_operation().tools.resolve(false)?;
+    let output = Command::new(&tools.ffmpeg).arg("-version").output()
+        .map_err(|e| format!("Cannot run {}: {}", tools.ffmpeg.display(), e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    println!("{}", stdout.lines().next().unwrap_or("ffmpeg version unknown"));
+    println!("  at {}", tools.ffmpeg.display());
+    Ok(())
+}
+
+/// A JSON document is an operation, or an array of them for a batch
+fn parse_json_operations(document: &str) -> Result<Vec<Operation>, Box<dyn std::error::Error>> {
+    let mut value: serde_json::Value = serde_json::from_str(document)?;
+    let operations = match value {
+        serde_json::Value::Array(ref mut operations) => operations.iter_mut().collect(),
+        ref mut operation => vec![operation],
+    };
+    // Transposes given as numbers are written by name
+    for operation in operations {
+        if let Some(transpose) = operation.get_mut("transpose") {
+            if let Some(t) = transpose.as_u64().and_then(Transpose::from_code) {
+                *transpose = serde_json::Value::String(t.as_ref().to_string());
+            }
+        }
+    }
+    let operations = if value.is_array() {
+        serde_json::from_value::<Vec<Operation>>(value)?
+    } else {
+        vec![serde_json::from_value::<Operation>(value)?]
+    };
+    if operations.is_empty() {
+        return Err("No operations were given".into());
+    }
+    Ok(operations)
+}
+
+/// Run one operation.  In a batch, `batch_index` numbers it, and its
+/// intermediate files go in a subdirectory of its own.
+fn run_operation(mut operation: Operation, json_flag: bool, env_level: Option<LogLevel>,
+                 batch_index: Option<usize>) -> Result<(), Box<dyn std::error::Error>>
+{
+    let started = Instant::now();
+    operation.json_output |= json_flag;
+    log::set_level(operation.log_level.or(env_level).unwrap_or_default());
+
+    if log::enabled(LogLevel::Verbose) {
+        println!("Operation is: {:?}", operation);
+    }
     //println!("{}", ron::ser::to_string::<Operation>(&operation)?);
 
-    // concatenation of inputs
+    operation.resolve_auto_codecs();
+    operation.validate()?;
+    operation.round_scale_to_even();
+    operation.check_inputs()?;
+
+    let tools = operation.tools.resolve(operation.cpulimit.is_some())?;
+    if let Err(e) = operation.check_encoders(&tools) {
+        let fallback = match operation.use_fallback() {
+            Some(fallback) => fallback,
+            None => return Err(e),
+        };
+        println!("WARNING: {}  Using the fallback_codec, {}, instead.", e, fallback.as_ref());
+        operation.check_encoders(&tools)?;
+    }
+    operation.check_crop(&tools)?;
+    operation.check_audio_track(&tools)?;
+    operation.silent = operation.probe_silent(&tools)?;
+    if operation.silent && (operation.audio_only || operation.still_image.is_some()) {
+        return Err("The input has no audio to output".into());
+    }
+    if operation.silent && log::enabled(LogLevel::Normal) {
+        println!("The input has no audio, so none will be output");
+    }
+
+    // (a re-encoded concat was already rotated by ffmpeg)
+    if operation.rotated_upright() && operation.video_codec != VCodec::Copy
+        && !operation.reencodes_concat() && !operation.audio_only
+        && operation.still_image.is_none()
+    {
+        match probe::rotation(&tools, &operation.inputs[0].path) {
+            Ok(degrees) => operation.source_rotation = Some(degrees),
+            Err(e) => println!("WARNING: Could not probe rotation of {}, so it will \
+                                not be corrected: {}", operation.inputs[0].path, e),
+        }
+    }
+
+    // (a re-encoded concat already has a constant rate)
+    if !operation.vfr_sync && operation.video_codec != VCodec::Copy
+        && !operation.audio_only && !operation.reencodes_concat()
+        && operation.still_image.is_none()
     {
-        let mut concat_list_file = File::create("concat.txt")?;
-        for input in &operation.inputs {
-            writeln!(concat_list_file, "file '{}'", input)?;
-        }
-        let mut cmd = Command::new(crate::FFMPEG_PATH);
-        cmd.arg("-f").arg("concat")
-            .arg("-i").arg("concat.txt")
-            .arg("-c").arg("copy")
-            .arg("concat.mp4");
-        let _ = run_cmd(cmd);
-    }
-
-    let title = operation.title
-        .replace("/", "-")
-        .replace(" ", "_")
-        .to_string();
-    let output = format!("{}.{}", title, operation.container.extension());
-    let pass1speed = 4;
-    let pass2speed = if operation.scale.0 < 1024 { 1 } else { 2 };
-
-    // Analyze loudness
-    let loudnorm = if operation.loudnorm {
-        Some(Loudnorm::from_analyze("concat.mp4", operation.cpulimit))
+        match probe::variable_frame_rate(&tools, &operation.inputs[0].path) {
+            Ok(true) => println!("WARNING: {} has a variable frame rate, so the audio \
+                                  may drift out of sync.  Set vfr_sync: true to keep \
+                                  them together.", operation.inputs[0].path),
+            Ok(false) => { },
+            Err(e) => println!("WARNING: Could not probe the frame rate of {}: {}",
+                               operation.inputs[0].path, e),
+        }
+    }
+
+    if operation.keep_color && operation.video_codec != VCodec::Copy
+        && !operation.audio_only && !operation.reencodes_concat()
+        && operation.still_image.is_none()
+    {
+        match probe::color(&tools, &operation.inputs[0].path) {
+            Ok(color) => {
+                if color.is_hdr() && color.mastering.is_some()
+                    && !video::takes_mastering(&operation)
+                {
+                    println!("WARNING: The HDR mastering metadata can only be given to \
+                              the H265 and SvtAv1 software encoders, so it may be lost");
+                }
+                operation.source_color = Some(color);
+            },
+            Err(e) => println!("WARNING: Could not probe the colours of {}, so they \
+                                will not be kept: {}", operation.inputs[0].path, e),
+        }
+    }
+
+    if operation.scale_is_auto() && !operation.audio_only {
+        let picture = operation.still_image.clone()
+            .unwrap_or_else(|| operation.inputs[0].path.clone());
+        match probe::video_size(&tools, &picture) {
+            Ok(size) => operation.source_size = Some(size),
+            Err(e) => println!("WARNING: Could not probe size of {}, assuming 16:9: {}",
+                               picture, e),
+        }
+    }
+
+    let mut work_dir = operation.work_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+    if let Some(n) = batch_index {
+        work_dir = work_dir.join(format!("batch{}", n));
+    }
+    let output_dir = operation.output_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+    std::fs::create_dir_all(&work_dir)?;
+    std::fs::create_dir_all(&output_dir)?;
+    let concat_list = work_dir.join("concat.txt").to_string_lossy().to_string();
+    // Matroska can hold whatever streams the inputs have (mp4 can't hold
+    // Vorbis, for one), so the joined copy never fails on that
+    let concat_file = work_dir.join("concat.mkv").to_string_lossy().to_string();
+    // Named for this run, so that encodes sharing the work_dir don't
+    // read each other's first pass
+    let passlogfile = work_dir
+        .join(format!("{}-{}-ffmpeg2pass",
+                      sanitize_filename(&operation.title, operation.keep_spaces),
+                      std::process::id()))
+        .to_string_lossy().to_string();
+
+    // A dry run leaves concat.txt for the printed commands to use
+    let mut cleanup = Cleanup::new(operation.cleanup && !operation.dry_run);
+    cleanup.prefix(&passlogfile);
+    if batch_index.is_some() {
+        cleanup.dir(&work_dir);
+    }
+
+    // concatenation of inputs, unless there is only one
+    let source = if operation.inputs.len() == 1 {
+        operation.inputs[0].path.clone()
+    } else if operation.reencodes_concat() {
+        cleanup.file(&concat_file);
+        run_cmd(build_concat_reencode_cmd(&tools, &operation, &concat_file),
+                operation.dry_run, operation.timeout())?;
+        concat_file
+    } else {
+        cleanup.file(&concat_list);
+        cleanup.file(&concat_file);
+        check_inputs_match(&tools, &operation.inputs)?;
+        let mut concat_list_file = File::create(&concat_list)?;
+        for input in operation.inputs.iter().map(|clip| &clip.path) {
+            // Relative paths would be taken relative to the list file
+            let input = std::fs::canonicalize(input)?;
+            writeln!(concat_list_file, "file {}",
+                     concat_escape(&concat_path(&input)))?;
+        }
+        // (replacing any left by a run that failed)
+        let mut cmd = Command::new(&tools.ffmpeg);
+        cmd.arg("-y")
+            .arg("-f").arg("concat")
+            .arg("-safe").arg("0")
+            .arg("-i").arg(&concat_list);
+        if operation.stream_mapping != StreamMapping::DefaultOnly {
+            cmd.arg("-map").arg("0");
+        } else {
+            // keep every audio track, in case one other than the first is
+            // wanted, and allow for inputs that are only audio
+            cmd.arg("-map").arg("0:v:0?")
+                .arg("-map").arg("0:a?");
+            if operation.subtitles == Subtitles::Copy {
+                cmd.arg("-map").arg("0:s?");
+            }
+        }
+        cmd.arg("-c").arg("copy")
+            .arg(&concat_file);
+        run_cmd(cmd, operation.dry_run, operation.timeout())?;
+        concat_file
+    };
+
+    let mut stem = output_stem(&operation)?;
+    if operation.preview_secs.is_some() {
+        stem.push_str("_preview");
+    }
+    let output = match operation.output {
+        Some(_) if operation.to_stdout() => "pipe:1".to_string(),
+        Some(ref output) => output.clone(),
+        None => output_dir.join(format!("{}.{}", stem, operation.extension()))
+            .to_string_lossy().to_string(),
+    };
+    if !operation.overwrite && !operation.to_stdout() && Path::new(&output).exists() {
+        return Err(format!("Output {} already exists.  Set overwrite: true to replace it.",
+                           output).into());
+    }
+
+    // Expected output length, for reporting progress
+    let total_secs = if operation.dry_run {
+        None
+    } else {
+        let speed = operation.speed.unwrap_or(1.0);
+        let probed = probe::duration(&tools, &source)
+            .map(|d| d - operation.start.unwrap_or(0.0));
+        match (operation.input_duration(), probed) {
+            // (a preview may be longer than the input)
+            (Some(d), Ok(probed)) => Some(d.min(probed) / speed),
+            (Some(d), Err(_)) => Some(d / speed),
+            (None, Ok(probed)) => Some(probed / speed),
+            (None, Err(e)) => {
+                println!("WARNING: Could not determine duration, so no progress \
+                          will be reported: {}", e);
+                None
+            }
+        }
+    };
+    if total_secs.is_none() && operation.fade_out.is_some() && !operation.dry_run {
+        return Err("fade_out needs the duration, which could not be determined".into());
+    }
+    if total_secs.is_none() && operation.still_image.is_some() && !operation.dry_run {
+        return Err("still_image needs the duration, which could not be determined".into());
+    }
+    if operation.target_size_mb.is_some() && !operation.dry_run {
+        operation.output_secs = Some(total_secs.ok_or(
+            "target_size_mb needs the duration, which could not be determined")?);
+        if video::target_bitrate(&operation) == 0 {
+            return Err("target_size_mb is too small to hold even the audio".into());
+        }
+    }
+    // (there is no target bitrate to estimate from with constant quality)
+    if let (Some(secs), false) = (total_secs, operation.video_codec == VCodec::Copy
+                                  || operation.encode_mode == EncodeMode::ConstantQuality
+                                  || operation.audio_only) {
+        video::warn_if_capped(&operation);
+        warn_if_oversized(&operation, secs);
+    }
+    // (operations in a batch may be running at once)
+    let progress = |label: &str| total_secs.map(|total| Progress {
+        label: match batch_index {
+            Some(n) => format!("Operation {} {}", n, label),
+            None => label.to_string(),
+        },
+        total,
+    });
+
+    // Analyze loudness.  Copied audio is not filtered, so it would not be
+    // applied.
+    let copies_audio = operation.audio_codec == ACodec::Copy;
+    if operation.loudnorm && copies_audio && log::enabled(LogLevel::Normal) {
+        println!("The audio is copied, so it will not be loudness normalized");
+    }
+    let loudnorm = if operation.loudnorm && !operation.silent && !copies_audio {
+        Some(Loudnorm::from_analyze(&tools, &operation, &source)?)
     } else {
         None
     };
 
-    // Pass 1
-    let mut pass1 = build_cmd(&operation, loudnorm.as_ref(), "concat.mp4");
-    pass1.arg("-pass").arg("1")
-        .arg("-speed").arg(&*format!("{}", pass1speed))
-        .arg(&*output);
-    let _ = run_cmd(pass1);
+    let encoded = encode(&tools, &operation, loudnorm.as_ref(), &source, total_secs,
+                         &passlogfile, &output, &progress);
+    let stderr = match encoded {
+        // Rather than leave it looking complete
+        Err(e) if run::interrupted() => {
+            if !operation.to_stdout() {
+                let _ = std::fs::remove_file(&output);
+            }
+            return Err(e);
+        },
+        // (what was written to stdout can't be taken back)
+        Err(e) if operation.fallback_codec.is_some() && !operation.to_stdout() => {
+            let fallback = operation.use_fallback().expect("there is a fallback");
+            println!("WARNING: The encode failed, so retrying with the fallback_codec, \
+                      {}: {}", fallback.as_ref(),
+                     e.to_string().lines().next().unwrap_or(""));
+            // Without overwrite, ffmpeg would refuse to replace what the
+            // failed encode left
+            let _ = std::fs::remove_file(&output);
+            encode(&tools, &operation, loudnorm.as_ref(), &source, total_secs,
+                   &passlogfile, &output, &progress)?
+        },
+        encoded => encoded?,
+    };
+
+    let output_loudness = match loudnorm {
+        Some(ref loudnorm) if !operation.dry_run => {
+            loudnorm.check_fallback(&stderr);
+            Loudnorm::output_loudness(&stderr)
+        },
+        _ => None,
+    };
+    if let (Some(o), true) = (output_loudness, log::enabled(LogLevel::Normal)) {
+        println!("Output loudness: {} LUFS integrated, {} dBTP true peak, {} LU range",
+                 o.i, o.tp, o.lra);
+    }
+
+    // (a dry run has no output to go by, so uses the inputs)
+    let image_secs = match total_secs {
+        None if operation.dry_run
+            && (operation.thumbnail.is_some() || operation.sprites.is_some()) => {
+            dry_run_secs(&tools, &operation)
+        },
+        total_secs => total_secs,
+    };
 
-    // Pass 2
-    let mut pass2 = build_cmd(&operation, loudnorm.as_ref(), "concat.mp4");
-    pass2.arg("-pass").arg("2")
-        .arg("-speed").arg(&*format!("{}", pass2speed))
-        .arg(&*output);
-    let _ = run_cmd(pass2);
+    if let Some(ref spec) = operation.thumbnail {
+        let image = output_dir.join(format!("{}.jpg", stem))
+            .to_string_lossy().to_string();
+        match image_secs {
+            Some(secs) => {
+                let cmd = thumbnail::build_cmd(&tools, spec, &output, secs, &image);
+                run_cmd(cmd, operation.dry_run, operation.timeout())?;
+            },
+            None => println!("WARNING: Skipping thumbnail, since the duration is unknown"),
+        }
+    }
+
+    if let Some(ref spec) = operation.sprites {
+        match image_secs {
+            Some(secs) => {
+                let tile_size = spec.tile_size(operation.output_size());
+                let sheets = output_dir.join(&stem).to_string_lossy().to_string();
+                let cmd = sprites::build_cmd(&tools, spec, &output, tile_size, &sheets);
+                run_cmd(cmd, operation.dry_run, operation.timeout())?;
+                if !operation.dry_run {
+                    let vtt = output_dir.join(format!("{}_sprites.vtt", stem));
+                    std::fs::write(&vtt, sprites::vtt(spec, secs, tile_size, &stem))?;
+                }
+            },
+            None => println!("WARNING: Skipping sprites, since the duration is unknown"),
+        }
+    }
+
+    cleanup.succeeded();
+
+    if operation.json_output && !operation.dry_run {
+        let summary = Summary::new(&tools, &output, total_secs, loudnorm.as_ref(),
+                                   output_loudness, started.elapsed())?;
+        run::println_output(&serde_json::to_string(&summary)?)?;
+    }
 
     Ok(())
 }
 
-fn build_cmd(operation: &Operation, loudnorm: Option<&Loudnorm>,
-             concat_file: &str) -> Command {
-    let mut command = Command::new(crate::CPULIMIT_PATH);
+/// The output length worked out from the inputs, for a dry run, in which
+/// there is no joined source to probe
+fn dry_run_secs(tools: &ToolPaths, operation: &Operation) -> Option<f64> {
+    let mut secs = 0.0;
+    for clip in &operation.inputs {
+        let duration = probe::duration(tools, &clip.path).ok()?;
+        let end = clip.end.map_or(duration, |end| end.min(duration));
+        secs += (end - clip.start.unwrap_or(0.0)).max(0.0);
+    }
+    secs -= operation.start.unwrap_or(0.0);
+    if let Some(duration) = operation.input_duration() {
+        secs = secs.min(duration);
+    }
+    Some(secs.max(0.0) / operation.speed.unwrap_or(1.0))
+}
+
+/// Encode the source to the output, in one pass or two, returning the
+/// stderr of the pass that writes the output
+#[allow(clippy::too_many_arguments)]
+fn encode(tools: &ToolPaths, operation: &Operation, loudnorm: Option<&Loudnorm>,
+          source: &str, total_secs: Option<f64>, passlogfile: &str, output: &str,
+          progress: &dyn Fn(&str) -> Option<Progress>)
+          -> Result<String, Box<dyn std::error::Error>>
+{
+    if two_pass(operation) {
+        // Pass 1
+        let pass1 = build_pass1(tools, operation, source, total_secs, passlogfile);
+        run_cmd_with_progress(pass1, operation.dry_run, operation.timeout(),
+                              progress("Pass 1").as_ref())?;
+
+        // Pass 2
+        let pass2 = build_pass2(tools, operation, loudnorm, source, total_secs,
+                                passlogfile, output);
+        if operation.to_stdout() {
+            run_cmd_to_stdout(pass2, operation.dry_run, operation.timeout())
+        } else {
+            run_cmd_with_progress(pass2, operation.dry_run, operation.timeout(),
+                                  progress("Pass 2").as_ref())
+        }
+    } else {
+        let mut encode = build_cmd(tools, operation, loudnorm, source,
+                                   total_secs, Pass::Only);
+        encode.arg(output);
+        if operation.to_stdout() {
+            run_cmd_to_stdout(encode, operation.dry_run, operation.timeout())
+        } else {
+            run_cmd_with_progress(encode, operation.dry_run, operation.timeout(),
+                                  progress("Encode").as_ref())
+        }
+    }
+}
+
+/// Whether the video is encoded in two passes, with `build_pass1` first.
+/// Hardware encoders do any second pass internally.
+fn two_pass(operation: &Operation) -> bool {
+    operation.encode_mode == EncodeMode::TwoPass
+        && !operation.audio_only
+        && operation.hwaccel.is_none()
+        && operation.video_codec.supports_two_pass()
+}
+
+/// The output filename without its extension.  Only the title is
+/// sanitized; the rest of the template is used as written.
+fn output_stem(operation: &Operation) -> Result<String, Box<dyn std::error::Error>> {
+    let title = sanitize_filename(&operation.title, operation.keep_spaces);
+    let template = match operation.output_template {
+        Some(ref template) => template,
+        None => return Ok(title),
+    };
+    let mut stem = String::new();
+    let mut rest = template.as_str();
+    while let Some(open) = rest.find('{') {
+        stem.push_str(&rest[..open]);
+        let close = open + rest[open..].find('}')
+            .ok_or_else(|| format!("Unclosed {{ in output_template {}", template))?;
+        let value = match &rest[open + 1..close] {
+            "title" => title.clone(),
+            "codec" => operation.video_codec.as_ref().to_lowercase(),
+            "quality" => operation.video_quality.as_ref().to_lowercase(),
+            "width" => format!("{}", operation.output_size().0),
+            "height" => format!("{}", operation.output_size().1),
+            "date" => today(),
+            "container" => operation.extension().to_string(),
+            name => return Err(format!("Unknown placeholder {{{}}} in output_template",
+                                       name).into()),
+        };
+        stem.push_str(&value);
+        rest = &rest[close + 1..];
+    }
+    stem.push_str(rest);
+    Ok(stem)
+}
 
-    command.arg("-l").arg(&*format!("{}", operation.cpulimit))
-        .arg(crate::FFMPEG_PATH)
-        .arg("-y")
-        .arg("-i").arg(concat_file);
+/// Today's date (UTC) as YYYY-MM-DD
+fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / 86400)
+        .unwrap_or(0);
+    // Howard Hinnant's civil_from_days, for days since 1970 (so no
+    // negative eras)
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
 
-    let mut audio_filters: Vec<String> = Vec::new();
+/// Make a title safe to use as a filename on Unix, Windows and FAT
+/// filesystems.  Reserved characters become '-', runs of whitespace
+/// (including control characters) become a single space or '_', and
+/// leading dots and trailing dots or spaces are removed.
+fn sanitize_filename(title: &str, keep_spaces: bool) -> String {
+    let space = if keep_spaces { ' ' } else { '_' };
+    let mut sanitized = String::with_capacity(title.len());
+    let mut in_whitespace = false;
+    for c in title.chars() {
+        if c.is_whitespace() || c.is_control() {
+            if !in_whitespace {
+                sanitized.push(space);
+            }
+            in_whitespace = true;
+            continue;
+        }
+        in_whitespace = false;
+        if "/\\:*?\"<>|".contains(c) {
+            sanitized.push('-');
+        } else {
+            sanitized.push(c);
+        }
+    }
+    let sanitized = sanitized
+        .trim_start_matches(['.', space])
+        .trim_end_matches(['.', space]);
+    if sanitized.is_empty() {
+        "untitled".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+/// Estimate the output size from the target bitrates, and warn if it is
+/// larger than the inputs were, which usually means the quality setting is
+/// higher than the source can benefit from
+fn warn_if_oversized(operation: &Operation, secs: f64) {
+    let audio_bitrate = audio::bitrate(operation.audio_codec, operation.audio_quality)
+        .filter(|_| !operation.silent)
+        .unwrap_or(0) as f64 * 1000.0;
+    let video_bitrate = video::target_bitrate(operation) as f64;
+    let estimate = ((video_bitrate + audio_bitrate) * secs / 8.0) as u64;
+    if log::enabled(LogLevel::Normal) {
+        println!("Estimated output size = {} MB", estimate / 1_000_000);
+    }
+
+    let input_size: u64 = operation.inputs.iter()
+        .filter_map(|clip| std::fs::metadata(&clip.path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    if estimate > input_size {
+        println!("WARNING: Estimated output size of {} MB is larger than the {} MB \
+                  of input.  Consider a lower quality.",
+                 estimate / 1_000_000, input_size / 1_000_000);
+    }
+}
+
+/// Stream copying with the concat demuxer only works if every input has
+/// the same stream layout and parameters
+fn check_inputs_match(tools: &ToolPaths, inputs: &[InputClip])
+                      -> Result<(), Box<dyn std::error::Error>>
+{
+    let first = probe::stream_summary(tools, &inputs[0].path)?;
+    for input in inputs[1..].iter().map(|clip| &clip.path) {
+        let summary = probe::stream_summary(tools, input)?;
+        if summary != first {
+            return Err(format!(
+                "Input {} has different streams than {}, so they cannot be \
+                 concatenated by copying.  Set concat_reencode: true.\n\
+                 {}:\n{}\n{}:\n{}",
+                input, inputs[0].path, inputs[0].path, first, input, summary).into());
+        }
+    }
+    Ok(())
+}
+
+/// Join heterogeneous or trimmed inputs with the concat filter,
+/// normalizing each to a common size, frame rate and audio format on the
+/// way.  The intermediate is high quality since it is encoded again
+/// afterwards.
+fn build_concat_reencode_cmd(tools: &ToolPaths, operation: &Operation,
+                             concat_file: &str) -> Command {
+    let mut command = ffmpeg_cmd(tools, operation.cpulimit);
+    command.arg("-y");
+    for clip in &operation.inputs {
+        // Seeking the input, which is accurate since it is decoded
+        if let Some(start) = clip.start {
+            command.arg("-ss").arg(&*format!("{}", start));
+        }
+        if let Some(end) = clip.end {
+            command.arg("-to").arg(&*format!("{}", end));
+        }
+        command.arg("-i").arg(&clip.path);
+    }
+
+    // Normalize to the output size as it is before transposing
+    let (w, h) = operation.output_size();
+    let (w, h) = match operation.transpose {
+        Some(t) if t.turns_quarter() => (h, w),
+        _ => (w, h),
+    };
+
+    // Crop is in source pixels, so it has to happen before normalizing,
+    // as does deinterlacing before that
+    let mut crop = match operation.deinterlace {
+        Some(deinterlace) => format!("{},", deinterlace.filter()),
+        None => String::new(),
+    };
+    if let Some((cw, ch, cx, cy)) = operation.crop {
+        crop.push_str(&format!("crop={}:{}:{}:{},", cw, ch, cx, cy));
+    }
+
+    let mut filters: Vec<String> = Vec::new();
+    let mut concat_inputs = String::new();
+    for i in 0..operation.inputs.len() {
+        filters.push(format!(
+            "[{i}:v]{crop}scale={w}:{h}:force_original_aspect_ratio=decrease,\
+             pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={n}/{d}[v{i}]",
+            i=i, crop=crop, w=w, h=h, n=operation.video_fps.0, d=operation.video_fps.1));
+        concat_inputs.push_str(&format!("[v{i}]", i=i));
+        if !operation.silent {
+            filters.push(format!(
+                "[{i}:a:{t}]aresample=48000,aformat=channel_layouts=stereo[a{i}]",
+                i=i, t=operation.audio_track.unwrap_or(0)));
+            concat_inputs.push_str(&format!("[a{i}]", i=i));
+        }
+    }
+    if operation.silent {
+        filters.push(format!("{}concat=n={}:v=1:a=0[v]",
+                             concat_inputs, operation.inputs.len()));
+    } else {
+        filters.push(format!("{}concat=n={}:v=1:a=1[v][a]",
+                             concat_inputs, operation.inputs.len()));
+    }
+
+    command.arg("-filter_complex").arg(filters.join(";"))
+        .arg("-map").arg("[v]");
+    if !operation.silent {
+        command.arg("-map").arg("[a]")
+            .arg("-c:a").arg("aac")
+            .arg("-b:a").arg("256k");
+    }
+    command
+        .arg("-c:v").arg("libx264")
+        .arg("-preset").arg("veryfast")
+        .arg("-crf").arg("10")
+        .arg(concat_file);
+    command
+}
+
+/// A path as the concat demuxer should be given it.  Canonical paths on
+/// Windows have a \\?\ prefix that ffmpeg doesn't understand, and ffmpeg
+/// takes forward slashes there as well.
+fn concat_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if !cfg!(windows) {
+        return path.to_string();
+    }
+    let path = match path.strip_prefix(r"\\?\UNC\") {
+        Some(share) => format!(r"\\{}", share),
+        None => path.trim_start_matches(r"\\?\").to_string(),
+    };
+    path.replace('\\', "/")
+}
+
+/// Quote a path for an ffmpeg concat demuxer list.  Everything inside
+/// single quotes is literal (including backslashes), so only the single
+/// quote itself needs escaping, which is done outside of the quotes.
+fn concat_escape(path: &str) -> String {
+    format!("'{}'", path.replace("'", "'\\''"))
+}
+
+/// Start an ffmpeg command, wrapped in cpulimit if a limit was given
+fn ffmpeg_cmd(tools: &ToolPaths, cpulimit: Option<u32>) -> Command {
+    match (cpulimit, &tools.cpulimit) {
+        (Some(limit), Some(cpulimit_path)) => {
+            let mut command = Command::new(cpulimit_path);
+            command.arg("-l").arg(&*format!("{}", limit))
+                .arg(&tools.ffmpeg);
+            command
+        },
+        _ => Command::new(&tools.ffmpeg)
+    }
+}
+
+/// Add the input file, along with any trimming of it
+fn input_args(command: &mut Command, operation: &Operation, input: &str) {
+    if let Some(start) = operation.start {
+        command.arg("-ss").arg(&*format!("{}", start));
+    }
+    // Before -i, so that it applies to this input and not to any other
+    // input that follows
+    if let Some(duration) = operation.input_duration() {
+        command.arg("-t").arg(&*format!("{}", duration));
+    }
+    command.arg("-i").arg(input);
+}
+
+/// Fade filters for the video and for the audio.  The fade out is timed
+/// back from the end, so needs the output duration (unknown in a dry run).
+fn fade_filters(operation: &Operation, total_secs: Option<f64>) -> (Vec<String>, Vec<String>) {
+    let mut video_fades: Vec<String> = Vec::new();
+    let mut audio_fades: Vec<String> = Vec::new();
+    if let Some(d) = operation.fade_in {
+        video_fades.push(format!("fade=t=in:st=0:d={}", d));
+        audio_fades.push(format!("afade=t=in:st=0:d={}", d));
+    }
+    if let Some(d) = operation.fade_out {
+        let start = match total_secs {
+            Some(total) => format!("{:.3}", (total - d).max(0.0)),
+            None => "FADE_OUT_START".to_string(),
+        };
+        video_fades.push(format!("fade=t=out:st={}:d={}", start, d));
+        audio_fades.push(format!("afade=t=out:st={}:d={}", start, d));
+    }
+    (video_fades, audio_fades)
+}
+
+/// Where output that is not wanted is written
+const NULL_DEVICE: &str = if cfg!(windows) { "NUL" } else { "/dev/null" };
+
+/// The first of two passes, which only writes the log of statistics for
+/// the second.  Audio is left out and the output discarded.
+fn build_pass1(tools: &ToolPaths, operation: &Operation, source: &str,
+               total_secs: Option<f64>, passlogfile: &str) -> Command {
+    let mut command = build_cmd(tools, operation, None, source, total_secs, Pass::First);
+    command.arg("-pass").arg("1")
+        .arg("-passlogfile").arg(passlogfile)
+        .arg("-f").arg("null").arg(NULL_DEVICE);
+    command
+}
+
+/// The second of two passes, which writes the output
+fn build_pass2(tools: &ToolPaths, operation: &Operation, loudnorm: Option<&Loudnorm>,
+               source: &str, total_secs: Option<f64>, passlogfile: &str, output: &str)
+               -> Command {
+    let mut command = build_cmd(tools, operation, loudnorm, source, total_secs, Pass::Second);
+    command.arg("-pass").arg("2")
+        .arg("-passlogfile").arg(passlogfile)
+        .arg(output);
+    command
+}
+
+fn build_cmd(tools: &ToolPaths, operation: &Operation, loudnorm: Option<&Loudnorm>,
+             source: &str, total_secs: Option<f64>, pass: Pass) -> Command {
+    let mut command = ffmpeg_cmd(tools, operation.cpulimit);
+
+    // (progress goes to stdout, unless that is where the output goes)
+    let output_to_stdout = operation.to_stdout() && pass != Pass::First;
+    command.arg(if operation.overwrite { "-y" } else { "-n" });
+    if !output_to_stdout {
+        command.arg("-progress").arg("pipe:1");
+    }
+    if let Some(loglevel) = log::ffmpeg_loglevel() {
+        command.arg("-loglevel").arg(loglevel);
+    }
+    if operation.hwaccel == Some(HwAccel::Vaapi) && operation.video_codec != VCodec::Copy
+        && !operation.audio_only
+    {
+        command.arg("-vaapi_device")
+            .arg(operation.tools.vaapi_device.as_deref()
+                 .unwrap_or(video::DEFAULT_VAAPI_DEVICE));
+    }
+    // Set as the source's display rotation, which is carried through to the
+    // output as long as ffmpeg doesn't apply it to the pixels itself.
+    // ffmpeg takes it counter-clockwise.  When we have turned the pixels
+    // upright, the source's rotation must not be carried through.
+    if (operation.rotate_metadata.is_some() || operation.source_rotation.is_some())
+        && !operation.audio_only
+    {
+        command.arg("-noautorotate")
+            .arg("-display_rotation:v:0")
+            .arg(&*format!("{}", -operation.rotate_metadata.unwrap_or(0)));
+    }
+    input_args(&mut command, operation, source);
+    // Then the composite input, trimmed the same way, and the watermark
+    // image, each if any
+    if let Some(ref composite) = operation.composite {
+        input_args(&mut command, operation, composite.input());
+    }
+    let watermark_input = 1 + operation.composite.is_some() as usize;
+    if let Some(image) = operation.watermark.as_ref().and_then(|w| w.image()) {
+        command.arg("-i").arg(image);
+    }
+    // (a still_image has neither of those, so is input 1)
+    if let Some(ref image) = operation.still_image {
+        command.arg("-loop").arg("1")
+            .arg("-i").arg(image);
+    }
+
+    let mut audio_filters: Vec<String> = audio::pre_loudnorm_filters(operation);
     let mut video_filters: Vec<String> = Vec::new();
 
-    if operation.loudnorm {
-        audio_filters.push(loudnorm.unwrap().convert_af());
+    // (not given for the first pass, which has no audio)
+    if let Some(loudnorm) = loudnorm {
+        audio_filters.push(loudnorm.convert_af());
+    }
+
+    let (video_fades, audio_fades) = fade_filters(operation, total_secs);
+    audio_filters.extend(audio_fades);
+
+    // (when re-encoding the concat, deinterlacing and cropping were already
+    // done there)
+    if let (Some(deinterlace), false) = (operation.deinterlace, operation.reencodes_concat()) {
+        video_filters.push(deinterlace.filter().to_string());
+    }
+
+    // Upright before cropping, so the crop is of the picture as displayed
+    match operation.source_rotation {
+        Some(90) => video_filters.push("transpose=clock".to_string()),
+        Some(180) => video_filters.push("hflip,vflip".to_string()),
+        Some(270) => video_filters.push("transpose=cclock".to_string()),
+        _ => { },
+    }
+
+    if let (Some((w, h, x, y)), false) = (operation.crop, operation.reencodes_concat()) {
+        video_filters.push(format!("crop={}:{}:{}:{}", w, h, x, y));
+    }
+
+    // Before scaling, while the noise is still at its original size (and
+    // so easier to tell from detail)
+    if let Some(denoise) = operation.denoise {
+        video_filters.push(denoise.filter().to_string());
+    }
+
+    if let Some(eq) = video::eq_filter(operation) {
+        video_filters.push(eq);
     }
 
     if let Some(t) = operation.transpose {
-        video_filters.push(format!("transpose={}",t));
+        video_filters.push(t.filter().to_string());
+    }
+
+    // -2 lets ffmpeg keep the aspect ratio with an even dimension
+    let auto = |n: i32| if n <= 0 { -2 } else { n };
+    video_filters.push(format!("scale={}:{}",
+                               auto(operation.scale.0),
+                               auto(operation.scale.1)));
+
+    // Retime before the fps filter, which then drops (or duplicates)
+    // frames to bring the rate back to the one asked for
+    if let Some(speed) = operation.speed {
+        video_filters.push(format!("setpts=PTS/{}", speed));
     }
 
-    video_filters.push(format!("scale={}x{}",
-                               operation.scale.0,
-                               operation.scale.1));
+    video_filters.push(operation.fps_mode.filter(operation.video_fps));
+
+    // The composite input is joined on here, in a filtergraph below, so
+    // that what follows applies to the whole picture
+    let composite_at = video_filters.len();
+
+    // After scaling, so the text is sized for the output
+    if let Subtitles::BurnIn(ref path) = operation.subtitles {
+        video_filters.push(subtitle::burn_in_filter(path, operation.start, operation.speed));
+    }
+
+    // Also after scaling, so the watermark's size relative to the picture
+    // is predictable.  An image overlay needs a second input, so is joined
+    // on in a filtergraph below rather than going in this chain.
+    let overlay = match operation.watermark {
+        Some(ref watermark) if watermark.image().is_some() => Some(watermark.filter()),
+        Some(ref watermark) => {
+            video_filters.push(watermark.filter());
+            None
+        },
+        None => None,
+    };
+
+    // Joining on other inputs needs a filtergraph, which gives the video
+    // as [v]
+    let complex = overlay.is_some() || operation.composite.is_some();
 
-    video_filters.push(format!("fps=fps={}/{}",
-                               operation.video_fps.0,
-                               operation.video_fps.1));
+    // Stream selection.  Once any -map is given ffmpeg stops choosing
+    // streams itself, so everything wanted must be mapped.
+    let audio_track = operation.source_audio_track();
+    if operation.stream_mapping != StreamMapping::DefaultOnly {
+        operation.stream_mapping.map_args(&mut command, pass);
+        subtitle::copy(&mut command, operation.container);
+    } else if audio_track.is_some() || operation.subtitles == Subtitles::Copy || complex
+        || operation.still_image.is_some()
+    {
+        if !operation.audio_only {
+            command.arg("-map").arg(match () {
+                _ if complex => "[v]",
+                _ if operation.still_image.is_some() => "1:v",
+                _ => "0:v:0",
+            });
+        }
+        match (audio_track, pass) {
+            (_, Pass::First) => &mut command,
+            (Some(track), _) => command.arg("-map").arg(format!("0:a:{}", track)),
+            (None, _) => command.arg("-map").arg("0:a:0?"),
+        };
+        if operation.subtitles == Subtitles::Copy {
+            command.arg("-map").arg("0:s?");
+            subtitle::copy(&mut command, operation.container);
+        }
+    }
+
+    // The looped picture never ends, so the audio decides the length.  The
+    // first pass has no audio, so is given the length.
+    if operation.still_image.is_some() {
+        if pass == Pass::First {
+            command.arg("-t").arg(match total_secs {
+                Some(total) => format!("{:.3}", total),
+                None => "DURATION".to_string(),
+            });
+        } else {
+            command.arg("-shortest");
+        }
+    }
 
-    if operation.strip_metadata {
-        command.arg("-map_metadata").arg("-1")
-            .arg("-metadata").arg(format!("title={}",operation.title));
+    match (operation.strip_metadata, operation.keep_chapters) {
+        (true, false) => {
+            command.arg("-map_metadata").arg("-1")
+                .arg("-metadata").arg(format!("title={}",operation.title));
+        },
+        (true, true) => {
+            // Strip global and stream metadata, leaving chapter metadata
+            command.arg("-map_metadata:g").arg("-1")
+                .arg("-map_metadata:s").arg("-1")
+                .arg("-map_chapters").arg("0")
+                .arg("-metadata").arg(format!("title={}",operation.title));
+        },
+        (false, true) => {
+            command.arg("-map_chapters").arg("0");
+        },
+        (false, false) => { },
     }
 
-    if operation.audio_codec != ACodec::Copy {
+    if operation.audio_codec != ACodec::Copy && pass != Pass::First && !operation.silent {
         let af = audio_filters.join(",");
-        if af.len() > 0 {
+        if !af.is_empty() {
             command.arg("-af").arg(af);
         }
+        if let Some(channels) = operation.channels {
+            command.arg("-ac").arg(&*format!("{}", channels));
+        }
     }
 
-    if operation.video_codec != VCodec::Copy {
-        let vf = video_filters.join(",");
-        if vf.len() > 0 {
-            command.arg("-vf").arg(vf);
+    // The filters above all run in software, so the upload to the GPU
+    // comes last
+    let upload = if operation.hwaccel == Some(HwAccel::Vaapi) {
+        ",format=nv12,hwupload"
+    } else {
+        ""
+    };
+
+    if operation.audio_only {
+        command.arg("-vn");
+    } else if operation.video_codec != VCodec::Copy && !complex {
+        // Fade last, so that everything on the picture fades with it
+        video_filters.extend(video_fades);
+        command.arg("-vf").arg(format!("{}{}", video_filters.join(","), upload));
+    } else if operation.video_codec != VCodec::Copy {
+        // A chain of filters for each step that joins on another input
+        let mut chains: Vec<String> = Vec::new();
+        let mut labels = "[0:v]".to_string();
+        let mut filters: Vec<String> = video_filters[..composite_at].to_vec();
+        if let Some(ref composite) = operation.composite {
+            chains.push(format!("{}{}[main]", labels, filters.join(",")));
+            let (_, height) = operation.output_size();
+            chains.push(format!("[1:v]{}[other]",
+                                composite.input_filter(height, operation.speed,
+                                                       operation.video_fps)));
+            labels = composite.labels().to_string();
+            filters = vec![composite.filter()];
+        }
+        filters.extend(video_filters[composite_at..].iter().cloned());
+        if let Some(overlay) = overlay {
+            chains.push(format!("{}{}[base]", labels, filters.join(",")));
+            labels = format!("[base][{}:v]", watermark_input);
+            filters = vec![overlay];
         }
+        filters.extend(video_fades);
+        chains.push(format!("{}{}{}[v]", labels, filters.join(","), upload));
+        command.arg("-filter_complex").arg(chains.join(";"));
     }
 
     match operation.audio_codec {
+        // The first pass only looks at the video
+        _ if pass == Pass::First || operation.silent => {
+            command.arg("-an");
+        },
+        ACodec::Auto => unreachable!("Auto is resolved before encoding"),
         ACodec::Copy => {
             command.arg("-c:a").arg("copy");
         },
         ACodec::Opus => {
             audio::opus(&mut command, operation.audio_quality);
+        },
+        ACodec::Aac => {
+            audio::aac(&mut command, operation.audio_quality);
+        },
+        ACodec::Vorbis => {
+            audio::vorbis(&mut command, operation.audio_quality);
+        },
+        ACodec::Mp3 => {
+            audio::mp3(&mut command, operation.audio_quality);
+        },
+        ACodec::Flac => {
+            audio::flac(&mut command, operation.audio_quality);
+        },
+        ACodec::PcmS16 => {
+            audio::pcm_s16(&mut command);
         }
     }
 
     match operation.video_codec {
+        _ if operation.audio_only => { },
         VCodec::Copy => {
             command.arg("-c:v").arg("copy");
         },
         _ => {
-            video::vp9_or_av1(&mut command, &operation);
+            video::build_video_codec_args(&mut command, operation, pass);
+            if let Some(ref color) = operation.source_color {
+                video::color_args(&mut command, color);
+            }
+            if operation.vfr_sync {
+                command.arg("-fps_mode").arg("cfr");
+            }
         }
     }
 
+    // Move the index (moov atom) to the front, so that playback can start
+    // before the whole file has downloaded
+    if operation.faststart && pass != Pass::First
+        && matches!(operation.extension(), "mp4" | "mov" | "m4a")
+    {
+        command.arg("-movflags").arg("+faststart");
+    }
+
+    command.args(&operation.extra_args);
+
+    if output_to_stdout {
+        command.arg("-f").arg(operation.container.muxer());
+    }
+
     command
 }
 
-fn run_cmd(mut command: Command) -> String {
-    println!("{:?}", command);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let output = command.output()
-        .expect("failed to execute command");
+    #[test]
+    fn concat_escape_plain() {
+        assert_eq!(concat_escape("/videos/a.mp4"), "'/videos/a.mp4'");
+    }
+
+    #[test]
+    fn concat_escape_apostrophe() {
+        assert_eq!(concat_escape("/videos/c's.mp4"), r"'/videos/c'\''s.mp4'");
+    }
+
+    #[test]
+    fn concat_escape_spaces() {
+        assert_eq!(concat_escape("/my videos/a b.mp4"), "'/my videos/a b.mp4'");
+    }
+
+    #[test]
+    fn concat_escape_backslashes() {
+        assert_eq!(concat_escape(r"C:\videos\a.mp4"), r"'C:\videos\a.mp4'");
+    }
 
-    let stderr_str = String::from_utf8_lossy(&*output.stderr).to_string();
-    if ! output.status.success() {
-        panic!("Failed to run ffmpeg multi command.  Stderr follows.\n{}",
-               stderr_str);
+    #[test]
+    fn copied_video_has_no_first_pass() {
+        let mut operation = Operation {
+            encode_mode: EncodeMode::TwoPass,
+            video_codec: VCodec::Vp9,
+            ..Operation::default()
+        };
+        assert!(two_pass(&operation));
+        operation.video_codec = VCodec::Copy;
+        assert!(!two_pass(&operation));
     }
 
-    stderr_str
+    #[test]
+    fn upgrade_transpose_field() {
+        assert_eq!(upgrade_transpose("(\n    transpose: Some( 1 ),\n)"),
+                   "(\n    transpose: Some( Clock ),\n)");
+        assert_eq!(upgrade_transpose("(a: 1,transpose:Some(2))"),
+                   "(a: 1,transpose:Some(CounterClock))");
+        assert_eq!(upgrade_transpose("(transpose: Some(FlipH))"), "(transpose: Some(FlipH))");
+    }
+
+    #[test]
+    fn upgrade_transpose_leaves_strings_and_comments() {
+        let document = r##"(
+    title: "x \" transpose: Some(1)",
+    // transpose: Some(1)
+    /* transpose: Some(1) */
+    output: Some(r#"transpose: Some(1)"#),
+    transpose: Some(3),
+)"##;
+        assert_eq!(upgrade_transpose(document),
+                   document.replace("transpose: Some(3)", "transpose: Some(ClockFlip)"));
+    }
+
+    #[test]
+    fn sanitize_reserved_characters() {
+        assert_eq!(sanitize_filename("a/b\\c:d*e?f\"g<h>i|j", true), "a-b-c-d-e-f-g-h-i-j");
+    }
+
+    #[test]
+    fn sanitize_whitespace_and_control_characters() {
+        assert_eq!(sanitize_filename("a \t\n b\x07c", false), "a_b_c");
+    }
+
+    #[test]
+    fn sanitize_leading_and_trailing_dots() {
+        assert_eq!(sanitize_filename("..hidden. . ", true), "hidden");
+        assert_eq!(sanitize_filename("v1.2 final", true), "v1.2 final");
+    }
+
+    #[test]
+    fn sanitize_keep_spaces() {
+        assert_eq!(sanitize_filename("My  Video", true), "My Video");
+        assert_eq!(sanitize_filename("My  Video", false), "My_Video");
+    }
+
+    #[test]
+    fn sanitize_untitled() {
+        assert_eq!(sanitize_filename("", false), "untitled");
+        assert_eq!(sanitize_filename(" .. ", true), "untitled");
+    }
 }
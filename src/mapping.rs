@@ -0,0 +1,42 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+use crate::video::Pass;
+
+/// Which streams of the source go into the output
+#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Serialize, Deserialize)]
+pub enum StreamMapping {
+    /// The first video stream, the audio_track (or the first audio
+    /// stream), and subtitle streams if they are copied
+    #[default]
+    DefaultOnly,
+    /// Every stream (-map 0), e.g. each audio and subtitle track of an
+    /// archived film.  Mkv only.
+    KeepAll,
+    /// These stream specifiers, e.g. `["0:v:0", "0:a:1", "0:a:2", "0:s"]`
+    Explicit(Vec<String>),
+}
+
+impl StreamMapping {
+    /// The -map arguments, for other than DefaultOnly.  The first pass
+    /// keeps the same video streams, and nothing else.
+    pub fn map_args(&self, command: &mut Command, pass: Pass) {
+        match self {
+            StreamMapping::DefaultOnly => return,
+            StreamMapping::KeepAll => {
+                command.arg("-map").arg("0");
+            },
+            StreamMapping::Explicit(specifiers) => {
+                for specifier in specifiers {
+                    command.arg("-map").arg(specifier);
+                }
+            },
+        }
+        if pass == Pass::First {
+            command.arg("-sn");
+        }
+    }
+}
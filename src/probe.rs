@@ -0,0 +1,212 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::process::Command;
+use crate::run::CommandError;
+use crate::tools::ToolPaths;
+
+/// Run ffprobe quietly against an input, returning its stdout
+fn ffprobe(tools: &ToolPaths, input: &str, args: &[&str])
+           -> Result<String, Box<dyn std::error::Error>>
+{
+    let mut command = Command::new(&tools.ffprobe);
+    command.arg("-v").arg("error")
+        .args(args)
+        .arg(input);
+
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command.output()
+        .map_err(|e| CommandError::Spawn(program.clone(), e))?;
+
+    if ! output.status.success() {
+        return Err(Box::new(CommandError::Failed {
+            program,
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        }));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// A one-line-per-stream summary of the stream parameters that must match
+/// for inputs to be joined with the concat demuxer
+pub fn stream_summary(tools: &ToolPaths, input: &str)
+                      -> Result<String, Box<dyn std::error::Error>>
+{
+    ffprobe(tools, input, &[
+        "-of", "csv=p=0",
+        "-show_entries",
+        "stream=codec_type,codec_name,width,height,pix_fmt,time_base,sample_rate,channels",
+    ])
+}
+
+/// The width and height of the first video stream
+pub fn video_size(tools: &ToolPaths, input: &str)
+                  -> Result<(u32, u32), Box<dyn std::error::Error>>
+{
+    let output = ffprobe(tools, input, &[
+        "-select_streams", "v:0",
+        "-of", "csv=p=0:s=x",
+        "-show_entries", "stream=width,height",
+    ])?;
+    match output.find('x') {
+        None => Err(format!("Could not determine video size of {}", input).into()),
+        Some(i) => Ok((output[..i].parse()?, output[i+1..].parse()?))
+    }
+}
+
+/// Whether the first video stream has a variable frame rate, which shows
+/// as an average rate that differs from the base rate
+pub fn variable_frame_rate(tools: &ToolPaths, input: &str)
+                           -> Result<bool, Box<dyn std::error::Error>>
+{
+    let output = ffprobe(tools, input, &[
+        "-select_streams", "v:0",
+        "-of", "csv=p=0",
+        "-show_entries", "stream=r_frame_rate,avg_frame_rate",
+    ])?;
+    let rates: Vec<f64> = output.split(',')
+        .filter_map(|rate| {
+            let (n, d) = rate.trim().split_once('/')?;
+            let (n, d) = (n.parse::<f64>().ok()?, d.parse::<f64>().ok()?);
+            if d == 0.0 { None } else { Some(n / d) }
+        })
+        .collect();
+    match rates[..] {
+        [base, average] if base > 0.0 => Ok((base - average).abs() / base > 0.01),
+        _ => Err(format!("Could not determine frame rate of {}", input).into()),
+    }
+}
+
+/// How far the first video stream is rotated for display, in clockwise
+/// degrees from 0 to 270.  This is in the display matrix, or for older
+/// files a rotate tag.
+pub fn rotation(tools: &ToolPaths, input: &str)
+                -> Result<u32, Box<dyn std::error::Error>>
+{
+    let output = ffprobe(tools, input, &[
+        "-select_streams", "v:0",
+        "-of", "default=nw=1",
+        "-show_entries", "stream_side_data=rotation:stream_tags=rotate",
+    ])?;
+    let value = |key: &str| output.lines()
+        .find_map(|line| line.strip_prefix(key))
+        .and_then(|v| v.trim().parse::<f64>().ok());
+    // The display matrix rotation is counter-clockwise
+    let degrees = match (value("rotation="), value("TAG:rotate=")) {
+        (Some(ccw), _) => -ccw,
+        (None, Some(cw)) => cw,
+        (None, None) => 0.0,
+    };
+    Ok(((degrees / 90.0).round() as i32 * 90).rem_euclid(360) as u32)
+}
+
+/// How the first video stream's colours are to be interpreted, which for
+/// HDR includes the display it was mastered on
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Color {
+    pub primaries: Option<String>,
+    pub transfer: Option<String>,
+    pub space: Option<String>,
+    pub mastering: Option<Mastering>,
+    /// Maximum content light level and maximum frame-average light level,
+    /// in cd/m²
+    pub light_level: Option<(u32, u32)>,
+}
+
+/// Mastering display colour volume (SMPTE ST 2086)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mastering {
+    /// CIE 1931 (x, y) of each primary, and of the white point
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white: (f64, f64),
+    /// In cd/m²
+    pub max_luminance: f64,
+    pub min_luminance: f64,
+}
+
+impl Color {
+    /// Whether the transfer is an HDR one (PQ or HLG)
+    pub fn is_hdr(&self) -> bool {
+        matches!(self.transfer.as_deref(), Some("smpte2084") | Some("arib-std-b67"))
+    }
+}
+
+/// The colour description of the first video stream.  The HDR metadata is
+/// looked for in the stream, then in the first frame.
+pub fn color(tools: &ToolPaths, input: &str)
+             -> Result<Color, Box<dyn std::error::Error>>
+{
+    const SIDE_DATA: &str = "red_x,red_y,green_x,green_y,blue_x,blue_y,\
+                             white_point_x,white_point_y,max_luminance,min_luminance,\
+                             max_content,max_average";
+    let output = ffprobe(tools, input, &[
+        "-select_streams", "v:0",
+        "-read_intervals", "%+#1",
+        "-of", "default=nw=1",
+        "-show_entries", &format!("stream=color_primaries,color_transfer,color_space\
+                                   :stream_side_data={}:frame_side_data={}",
+                                  SIDE_DATA, SIDE_DATA),
+    ])?;
+    let text = |key: &str| output.lines()
+        .find_map(|line| line.strip_prefix(key).and_then(|v| v.strip_prefix('=')))
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && *v != "unknown" && *v != "reserved")
+        .map(str::to_string);
+    // Rationals like 34000/50000
+    let number = |key: &str| text(key).and_then(|v| match v.split_once('/') {
+        Some((n, d)) => match (n.parse::<f64>(), d.parse::<f64>()) {
+            (Ok(n), Ok(d)) if d != 0.0 => Some(n / d),
+            _ => None,
+        },
+        None => v.parse::<f64>().ok(),
+    });
+    let xy = |name: &str| Some((number(&format!("{}_x", name))?,
+                                number(&format!("{}_y", name))?));
+    let mastering = (|| Some(Mastering {
+        red: xy("red")?,
+        green: xy("green")?,
+        blue: xy("blue")?,
+        white: xy("white_point")?,
+        max_luminance: number("max_luminance")?,
+        min_luminance: number("min_luminance")?,
+    }))();
+    let light_level = match (number("max_content"), number("max_average")) {
+        (Some(cll), Some(fall)) => Some((cll as u32, fall as u32)),
+        _ => None,
+    };
+    Ok(Color {
+        primaries: text("color_primaries"),
+        transfer: text("color_transfer"),
+        space: text("color_space"),
+        mastering,
+        light_level,
+    })
+}
+
+/// Duration in seconds, from the container
+pub fn duration(tools: &ToolPaths, input: &str)
+                -> Result<f64, Box<dyn std::error::Error>>
+{
+    let output = ffprobe(tools, input, &[
+        "-of", "csv=p=0",
+        "-show_entries", "format=duration",
+    ])?;
+    output.parse::<f64>()
+        .map_err(|_| format!("Could not determine duration of {}", input).into())
+}
+
+/// The number of audio streams
+pub fn audio_stream_count(tools: &ToolPaths, input: &str)
+                          -> Result<u32, Box<dyn std::error::Error>>
+{
+    let output = ffprobe(tools, input, &[
+        "-select_streams", "a",
+        "-of", "csv=p=0",
+        "-show_entries", "stream=index",
+    ])?;
+    Ok(output.lines().filter(|line| !line.trim().is_empty()).count() as u32)
+}
@@ -0,0 +1,364 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::log::{self, LogLevel};
+
+/// Errors from running an external command
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command could not be started at all (e.g. not found)
+    Spawn(String, std::io::Error),
+
+    /// The command ran but exited unsuccessfully
+    Failed { program: String, code: Option<i32>, stderr: String },
+
+    /// The command ran for longer than it was allowed to, and was killed
+    TimedOut { program: String, timeout: Duration, stderr: String },
+
+    /// We were interrupted (e.g. by Ctrl-C), so the command was killed, or
+    /// not started
+    Interrupted(String),
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Spawn(program, e) =>
+                write!(f, "Failed to execute {}: {}", program, e),
+            CommandError::Failed { program, code: Some(code), stderr } =>
+                write!(f, "{} exited with code {}.  Stderr follows.\n{}",
+                       program, code, stderr),
+            CommandError::Failed { program, code: None, stderr } =>
+                write!(f, "{} was killed by a signal.  Stderr follows.\n{}",
+                       program, stderr),
+            CommandError::TimedOut { program, timeout, stderr } =>
+                write!(f, "{} was killed after running for more than {} seconds.  \
+                           Stderr follows.\n{}",
+                       program, timeout.as_secs(), stderr),
+            CommandError::Interrupted(program) =>
+                write!(f, "Interrupted, so {} was stopped", program),
+        }
+    }
+}
+
+impl std::error::Error for CommandError { }
+
+/// Progress reporting for an ffmpeg command that was given `-progress pipe:1`
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// Printed ahead of the percentage, e.g. "Pass 2"
+    pub label: String,
+
+    /// Expected seconds of output
+    pub total: f64,
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Catch Ctrl-C (and SIGTERM), so that the command running is killed and
+/// its partial output removed, rather than left looking complete.  A
+/// second one stops us at once.
+#[cfg(unix)]
+pub fn handle_interrupts() {
+    extern "C" fn handler(signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        // SAFETY: signal() is async-signal-safe
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+    // SAFETY: the handler does nothing that isn't async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as *const () as libc::sighandler_t);
+    }
+}
+
+/// Elsewhere Ctrl-C reaches the commands, which stop, and then so do we
+#[cfg(not(unix))]
+pub fn handle_interrupts() {
+}
+
+/// Whether we were interrupted, so should stop
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Our stdout, kept for ffmpeg to write the output to, once our own
+/// printing has been moved to stderr
+static OUTPUT: OnceLock<File> = OnceLock::new();
+
+/// Send everything printed from now on to stderr, keeping stdout for
+/// `run_cmd_to_stdout` and `println_output`
+#[cfg(unix)]
+pub fn reserve_stdout() -> std::io::Result<()> {
+    use std::os::unix::io::FromRawFd;
+    std::io::stdout().flush()?;
+    // SAFETY: dup() and dup2() have no memory safety requirements, and the
+    // duplicate is a new descriptor that nothing else owns
+    let output = unsafe {
+        let fd = libc::dup(1);
+        if fd < 0 || libc::dup2(2, 1) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd)
+    };
+    let _ = OUTPUT.set(output);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn reserve_stdout() -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+                            "Writing the output to stdout is only supported on Unix"))
+}
+
+/// Print a line to the stdout kept by `reserve_stdout`, or to stdout if it
+/// was not kept
+pub fn println_output(line: &str) -> std::io::Result<()> {
+    match OUTPUT.get() {
+        Some(mut output) => writeln!(output, "{}", line),
+        None => {
+            println!("{}", line);
+            Ok(())
+        },
+    }
+}
+
+/// Render a command as a line that can be pasted into a shell
+pub fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace("'", "'\\''"))
+    }
+}
+
+/// Run a command, returning its stderr.  In a dry run the command is only
+/// printed, and the returned stderr is empty.  If it runs for longer than
+/// the timeout, it is killed along with anything it started.
+pub fn run_cmd(command: Command, dry_run: bool, timeout: Option<Duration>)
+               -> Result<String, Box<dyn std::error::Error>>
+{
+    run_cmd_with_progress(command, dry_run, timeout, None)
+}
+
+/// Like `run_cmd`, but reports progress as ffmpeg writes it to stdout
+pub fn run_cmd_with_progress(command: Command, dry_run: bool,
+                             timeout: Option<Duration>, progress: Option<&Progress>)
+                             -> Result<String, Box<dyn std::error::Error>>
+{
+    run(command, dry_run, timeout, progress, false)
+}
+
+/// Like `run_cmd`, but ffmpeg writes to the stdout kept by
+/// `reserve_stdout`, so there is no progress to report
+pub fn run_cmd_to_stdout(command: Command, dry_run: bool, timeout: Option<Duration>)
+                         -> Result<String, Box<dyn std::error::Error>>
+{
+    run(command, dry_run, timeout, None, true)
+}
+
+fn run(mut command: Command, dry_run: bool, timeout: Option<Duration>,
+       progress: Option<&Progress>, to_stdout: bool)
+       -> Result<String, Box<dyn std::error::Error>>
+{
+    if dry_run || log::enabled(LogLevel::Normal) {
+        println!("{}", command_line(&command));
+    }
+    if dry_run {
+        return Ok(String::new());
+    }
+
+    let program = command.get_program().to_string_lossy().to_string();
+    if interrupted() {
+        return Err(Box::new(CommandError::Interrupted(program)));
+    }
+
+    // In its own process group, so that it can be killed along with the
+    // ffmpeg that cpulimit starts.  The terminal's Ctrl-C then only
+    // reaches us, and we kill the group.
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let stdout = match OUTPUT.get() {
+        Some(output) if to_stdout => Stdio::from(output.try_clone()
+            .map_err(|e| CommandError::Spawn(program.clone(), e))?),
+        _ => Stdio::piped(),
+    };
+    // Nothing is read from the terminal, which a background process group
+    // would be stopped for
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(stdout)
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| CommandError::Spawn(program.clone(), e))?;
+
+    // Drain stderr on another thread so ffmpeg can't block on a full pipe
+    let mut stderr = child.stderr.take().expect("stderr was piped");
+    let stderr_thread = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stderr.read_to_end(&mut buffer);
+        buffer
+    });
+
+    // And stdout, which is where progress is reported (unless it is the
+    // output)
+    let stdout = child.stdout.take();
+    let progress = progress.cloned();
+    // (the level is the operation's, on this thread)
+    let report = log::enabled(LogLevel::Normal);
+    let stdout_thread = thread::spawn(move || {
+        let stdout = match stdout {
+            Some(stdout) => stdout,
+            None => return,
+        };
+        let mut last_percent = None;
+        let mut secs = None;
+        let mut speed = None;
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            let progress = match progress {
+                Some(ref p) => p,
+                None => continue,
+            };
+            if let Some(s) = parse_out_time(&line) {
+                secs = Some(s);
+            } else if let Some(s) = line.strip_prefix("speed=") {
+                speed = parse_speed(s);
+            } else if line.starts_with("progress=") {
+                // The end of a block of progress lines
+                let secs = match secs {
+                    Some(secs) => secs,
+                    None => continue,
+                };
+                let percent = (secs / progress.total * 100.0).clamp(0.0, 100.0) as u32;
+                if last_percent != Some(percent) && report {
+                    match speed {
+                        Some(speed) => {
+                            let eta = (progress.total - secs).max(0.0) / speed;
+                            println!("{}: {}% (ETA {})", progress.label, percent,
+                                     format_eta(eta));
+                        },
+                        None => println!("{}: {}%", progress.label, percent),
+                    }
+                    last_percent = Some(percent);
+                }
+            }
+        }
+    });
+
+    let status = wait(&mut child, timeout)
+        .map_err(|e| CommandError::Spawn(program.clone(), e))?;
+    let _ = stdout_thread.join();
+    let stderr_str = String::from_utf8_lossy(
+        &stderr_thread.join().unwrap_or_default()).to_string();
+
+    let status = match (status, timeout) {
+        (Some(status), _) => status,
+        (None, _) if interrupted() => {
+            return Err(Box::new(CommandError::Interrupted(program)));
+        },
+        (None, Some(timeout)) => return Err(Box::new(CommandError::TimedOut {
+            program,
+            timeout,
+            stderr: stderr_str,
+        })),
+        (None, None) => unreachable!("only a timeout or interrupt stops the wait"),
+    };
+
+    if ! status.success() {
+        return Err(Box::new(CommandError::Failed {
+            program,
+            code: status.code(),
+            stderr: stderr_str
+        }));
+    }
+
+    if log::enabled(LogLevel::Verbose) {
+        print!("{}", stderr_str);
+    }
+
+    Ok(stderr_str)
+}
+
+/// Wait for the child to exit, polling so that it can be killed once the
+/// timeout is up, or if we are interrupted.  Returns None if it was killed.
+fn wait(child: &mut Child, timeout: Option<Duration>)
+        -> std::io::Result<Option<ExitStatus>>
+{
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if interrupted() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill_tree(child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Kill the child's whole process group.  Killing cpulimit alone would
+/// leave its ffmpeg running (or worse, stopped).
+#[cfg(unix)]
+fn kill_tree(child: &mut Child) {
+    // SAFETY: kill() has no memory safety requirements.  The group was
+    // created for the child when it was spawned, and it is not yet reaped.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_tree(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// Seconds of output written so far, from an ffmpeg `-progress` line.
+/// Despite its name, out_time_ms is in microseconds just like out_time_us.
+fn parse_out_time(line: &str) -> Option<f64> {
+    let micros = line.strip_prefix("out_time_us=")
+        .or_else(|| line.strip_prefix("out_time_ms="))?;
+    micros.trim().parse::<i64>().ok().map(|us| us as f64 / 1_000_000.0)
+}
+
+/// Seconds of output per second of encoding, from e.g. "1.3x".  ffmpeg
+/// reports "N/A" until it knows.
+fn parse_speed(speed: &str) -> Option<f64> {
+    speed.trim().strip_suffix('x')?.trim().parse::<f64>().ok()
+        .filter(|speed| *speed > 0.0)
+}
+
+/// e.g. "1h05m", "12m30s" or "45s"
+fn format_eta(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
@@ -0,0 +1,95 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+use crate::tools::ToolPaths;
+
+/// Frames taken at an interval through the video and tiled into sprite
+/// sheets, with a WebVTT file that tells a web player which part of which
+/// sheet to show when hovering over each point of the seek bar
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SpriteSpec {
+    /// Seconds between frames
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    /// Width of each frame in pixels
+    #[serde(default = "default_width")]
+    pub width: u32,
+    /// Frames across each sheet
+    #[serde(default = "default_tiles")]
+    pub columns: u32,
+    /// Frames down each sheet
+    #[serde(default = "default_tiles")]
+    pub rows: u32,
+}
+
+fn default_interval() -> f64 {
+    10.0
+}
+
+fn default_width() -> u32 {
+    160
+}
+
+fn default_tiles() -> u32 {
+    10
+}
+
+impl SpriteSpec {
+    /// The size of each frame, given the video's size
+    pub fn tile_size(&self, video: (u32, u32)) -> (u32, u32) {
+        let height = (self.width as u64 * video.1 as u64 / video.0.max(1) as u64) as u32;
+        (self.width, (height.div_ceil(2) * 2).max(2))
+    }
+
+    /// The name of the nth sheet (from 1) for a stem
+    pub fn sheet_name(stem: &str, n: u32) -> String {
+        format!("{}_sprites_{:03}.jpg", stem, n)
+    }
+}
+
+/// Build the command to make the sheets from an encoded video.  `sheets`
+/// is the path of the sheets without the number and extension, as
+/// `sheet_name` gives.
+pub fn build_cmd(tools: &ToolPaths, spec: &SpriteSpec, video: &str,
+                 tile_size: (u32, u32), sheets: &str) -> Command {
+    let mut command = Command::new(&tools.ffmpeg);
+    command.arg("-y")
+        .arg("-i").arg(video)
+        .arg("-vf").arg(format!("fps=1/{},scale={}:{},setsar=1,tile={}x{}",
+                                spec.interval, tile_size.0, tile_size.1,
+                                spec.columns, spec.rows))
+        .arg("-q:v").arg("3")
+        // (a % in the name would be taken as part of the pattern)
+        .arg(format!("{}_sprites_%03d.jpg", sheets.replace('%', "%%")));
+    command
+}
+
+/// The WebVTT file, with a cue for each frame pointing at its place on
+/// its sheet
+pub fn vtt(spec: &SpriteSpec, duration: f64, tile_size: (u32, u32), stem: &str) -> String {
+    let per_sheet = spec.columns * spec.rows;
+    let frames = (duration / spec.interval).ceil().max(1.0) as u32;
+    let mut vtt = "WEBVTT\n".to_string();
+    for i in 0..frames {
+        let start = i as f64 * spec.interval;
+        let end = (start + spec.interval).min(duration);
+        let tile = i % per_sheet;
+        vtt.push_str(&format!("\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                              timestamp(start), timestamp(end),
+                              SpriteSpec::sheet_name(stem, i / per_sheet + 1),
+                              tile % spec.columns * tile_size.0,
+                              tile / spec.columns * tile_size.1,
+                              tile_size.0, tile_size.1));
+    }
+    vtt
+}
+
+/// e.g. "01:02:03.456"
+fn timestamp(secs: f64) -> String {
+    let millis = (secs * 1000.0).round() as u64;
+    format!("{:02}:{:02}:{:02}.{:03}", millis / 3_600_000, millis / 60_000 % 60,
+            millis / 1000 % 60, millis % 1000)
+}
@@ -0,0 +1,101 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+use crate::Container;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Serialize, Deserialize)]
+pub enum Subtitles {
+    /// Subtitle streams are dropped
+    #[default]
+    None,
+    /// Subtitle streams from the input are kept (converted to mov_text for
+    /// Mp4/Mov, which cannot hold other subtitle formats)
+    Copy,
+    /// Render the subtitles from this file (e.g. an .srt) into the picture
+    BurnIn(String),
+}
+
+impl Subtitles {
+    pub fn supported_by(&self, container: Container) -> bool {
+        !matches!((self, container), (Subtitles::Copy, Container::Webm))
+    }
+}
+
+/// Codec arguments for copied subtitle streams (which must also be mapped)
+pub fn copy(command: &mut Command, container: Container) {
+    match container {
+        Container::Mp4 | Container::Mov => command.arg("-c:s").arg("mov_text"),
+        _ => command.arg("-c:s").arg("copy"),
+    };
+}
+
+/// The video filter that burns in a subtitle file.  The subtitles are
+/// timed from the start of the source, while the picture starts at 0 from
+/// `start` (seeking resets it) and is retimed by `speed`, so the picture
+/// is put back into the source's time around the filter.
+pub fn burn_in_filter(path: &str, start: Option<f64>, speed: Option<f64>) -> String {
+    let filter = format!("subtitles={}", filter_escape(path));
+    if start.is_none() && speed.is_none() {
+        return filter;
+    }
+    let start = start.unwrap_or(0.0);
+    let speed = speed.unwrap_or(1.0);
+    format!("setpts=PTS*{speed}+{start}/TB,{filter},setpts=(PTS-{start}/TB)/{speed}",
+            speed = speed, start = start, filter = filter)
+}
+
+/// Escape a value for use as a filter option within a filtergraph.  The
+/// filter option parser and then the filtergraph parser each unescape
+/// once, so it is escaped for the first and that again for the second.
+/// (Quoting would only be removed by one of them.)
+pub fn filter_escape(value: &str) -> String {
+    escape(&escape(value, ":"), "[],;")
+}
+
+/// Backslash the backslashes, quotes and these special characters
+fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || c == '\'' || special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_path_is_unchanged() {
+        assert_eq!(burn_in_filter("subs/a b.srt", None, None), "subtitles=subs/a b.srt");
+    }
+
+    #[test]
+    fn colon_and_quote() {
+        assert_eq!(burn_in_filter("a:b'c.srt", None, None), r"subtitles=a\\:b\\\'c.srt");
+    }
+
+    #[test]
+    fn windows_path() {
+        assert_eq!(burn_in_filter(r"C:\subs\a.srt", None, None), r"subtitles=C\\:\\\\subs\\\\a.srt");
+    }
+
+    #[test]
+    fn timed_from_the_source() {
+        assert_eq!(burn_in_filter("a.srt", Some(90.5), None),
+                   "setpts=PTS*1+90.5/TB,subtitles=a.srt,setpts=(PTS-90.5/TB)/1");
+        assert_eq!(burn_in_filter("a.srt", None, Some(2.0)),
+                   "setpts=PTS*2+0/TB,subtitles=a.srt,setpts=(PTS-0/TB)/2");
+    }
+
+    #[test]
+    fn filtergraph_separators() {
+        assert_eq!(filter_escape("a,b;c[d]"), r"a\,b\;c\[d\]");
+    }
+}
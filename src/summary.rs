@@ -0,0 +1,71 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::time::Duration;
+use serde::Serialize;
+use crate::audio::{Loudnorm, OutputLoudness};
+use crate::probe;
+use crate::tools::ToolPaths;
+
+/// The result of a successful run, for scripts to read
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub output: String,
+    /// Size of the output file in bytes
+    pub size: u64,
+    /// Duration of the output in seconds, if it could be determined
+    pub duration: Option<f64>,
+    /// Overall bitrate of the output in bits per second
+    pub bitrate: Option<u64>,
+    pub loudnorm: Option<LoudnormSummary>,
+    /// Wall clock seconds the whole run took
+    pub elapsed: f64,
+}
+
+/// What loudnorm measured of the input during analysis, and of the output
+/// while encoding
+#[derive(Debug, Serialize)]
+pub struct LoudnormSummary {
+    pub input_i: f64,
+    pub input_tp: f64,
+    pub input_lra: f64,
+    pub input_thresh: f64,
+    pub target_offset: f64,
+    /// None if loudnorm's report could not be read, as when logging Quiet
+    pub output: Option<OutputLoudness>,
+}
+
+impl Summary {
+    /// Gather the summary for an output.  The expected duration is used if
+    /// the output cannot be probed.
+    pub fn new(tools: &ToolPaths, output: &str, expected_secs: Option<f64>,
+               loudnorm: Option<&Loudnorm>, output_loudness: Option<OutputLoudness>,
+               elapsed: Duration)
+               -> Result<Summary, Box<dyn std::error::Error>>
+    {
+        let size = std::fs::metadata(output)?.len();
+        let duration = probe::duration(tools, output).ok().or(expected_secs);
+        let bitrate = duration
+            .filter(|secs| *secs > 0.0)
+            .map(|secs| (size as f64 * 8.0 / secs) as u64);
+        let loudnorm = match loudnorm {
+            Some(loudnorm) => Some(LoudnormSummary {
+                input_i: loudnorm.input_i.trim().parse()?,
+                input_tp: loudnorm.input_tp.trim().parse()?,
+                input_lra: loudnorm.input_lra.trim().parse()?,
+                input_thresh: loudnorm.input_thresh.trim().parse()?,
+                target_offset: loudnorm.target_offset.trim().parse()?,
+                output: output_loudness,
+            }),
+            None => None,
+        };
+        Ok(Summary {
+            output: output.to_string(),
+            size,
+            duration,
+            bitrate,
+            loudnorm,
+            elapsed: elapsed.as_secs_f64(),
+        })
+    }
+}
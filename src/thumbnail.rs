@@ -0,0 +1,53 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+use crate::tools::ToolPaths;
+
+/// A poster frame (1x1) or a contact sheet grid of frames taken evenly
+/// through the video, written as a JPEG
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct ThumbnailSpec {
+    pub columns: u32,
+    pub rows: u32,
+    /// Width of the whole image in pixels
+    pub width: u32,
+}
+
+impl ThumbnailSpec {
+    pub fn count(&self) -> u32 {
+        self.columns * self.rows
+    }
+}
+
+/// Build the command to make the thumbnail image from an encoded video of
+/// the given duration
+pub fn build_cmd(tools: &ToolPaths, spec: &ThumbnailSpec, video: &str,
+                 duration: f64, image: &str) -> Command {
+    let mut command = Command::new(&tools.ffmpeg);
+    command.arg("-y");
+
+    let tile_width = (spec.width / spec.columns.max(1)).max(2);
+    if spec.count() <= 1 {
+        // A third of the way in skips past any intro, then the thumbnail
+        // filter picks the most representative of the frames that follow
+        command
+            .arg("-ss").arg(&*format!("{:.3}", duration / 3.0))
+            .arg("-i").arg(video)
+            .arg("-vf").arg(format!("thumbnail,scale={}:-2", tile_width));
+    } else {
+        let interval = duration / spec.count() as f64;
+        command
+            .arg("-i").arg(video)
+            .arg("-vf").arg(format!("fps=1/{:.3},scale={}:-2,tile={}x{}",
+                                    interval, tile_width, spec.columns, spec.rows));
+    }
+
+    command
+        .arg("-frames:v").arg("1")
+        .arg("-q:v").arg("3")
+        .arg(image);
+    command
+}
@@ -0,0 +1,119 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use std::collections::HashSet;
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use serde::{Serialize, Deserialize};
+
+/// Locations of the external programs we drive.  Any path left unset is
+/// searched for in $PATH.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Tools {
+    #[serde(default)]
+    pub ffmpeg_path: Option<String>,
+
+    #[serde(default)]
+    pub cpulimit_path: Option<String>,
+
+    /// Defaults to the ffprobe alongside ffmpeg, if there is one
+    #[serde(default)]
+    pub ffprobe_path: Option<String>,
+
+    /// The DRM render node for VAAPI.  Defaults to /dev/dri/renderD128
+    #[serde(default)]
+    pub vaapi_device: Option<String>,
+}
+
+/// Tool paths after resolution, ready to be handed to `Command::new`
+#[derive(Debug, Clone)]
+pub struct ToolPaths {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+
+    /// Only resolved when a cpulimit was requested
+    pub cpulimit: Option<PathBuf>,
+}
+
+impl Tools {
+    pub fn resolve(&self, need_cpulimit: bool)
+                   -> Result<ToolPaths, Box<dyn std::error::Error>>
+    {
+        let cpulimit = if need_cpulimit {
+            Some(resolve_one(self.cpulimit_path.as_deref(), "cpulimit")?)
+        } else {
+            None
+        };
+        let ffmpeg = resolve_one(self.ffmpeg_path.as_deref(), "ffmpeg")?;
+        let sibling_ffprobe = ffmpeg.with_file_name(
+            format!("ffprobe{}", env::consts::EXE_SUFFIX));
+        let ffprobe = match self.ffprobe_path {
+            None if sibling_ffprobe.is_file() => sibling_ffprobe,
+            _ => resolve_one(self.ffprobe_path.as_deref(), "ffprobe")?,
+        };
+        Ok(ToolPaths {
+            ffmpeg,
+            ffprobe,
+            cpulimit,
+        })
+    }
+}
+
+impl ToolPaths {
+    /// The names of the encoders ffmpeg was built with
+    pub fn encoders(&self) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+        let output = Command::new(&self.ffmpeg)
+            .arg("-hide_banner").arg("-encoders")
+            .output()
+            .map_err(|e| format!("Cannot run {}: {}", self.ffmpeg.display(), e))?;
+        if !output.status.success() {
+            return Err(format!("{} -encoders failed", self.ffmpeg.display()).into());
+        }
+        // A legend, then a line of dashes, then lines like
+        // " V....D libx264              libx264 H.264 / AVC ..."
+        Ok(String::from_utf8_lossy(&output.stdout)
+           .lines()
+           .skip_while(|line| !line.trim_start().starts_with("---"))
+           .skip(1)
+           .filter_map(|line| line.split_whitespace().nth(1))
+           .map(|name| name.to_string())
+           .collect())
+    }
+}
+
+fn resolve_one(configured: Option<&str>, name: &str)
+               -> Result<PathBuf, Box<dyn std::error::Error>>
+{
+    match configured {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => which(name)
+            .ok_or_else(|| format!("Could not find {} in $PATH", name).into())
+    }
+}
+
+/// Search $PATH for an executable file with the given name (on Windows,
+/// like `where` does, also with each of the extensions in %PATHEXT%)
+pub fn which(name: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    let names = executable_names(name);
+    env::split_paths(&path)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(windows)]
+fn executable_names(name: &str) -> Vec<String> {
+    let pathext = env::var("PATHEXT")
+        .unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+    std::iter::once(name.to_string())
+        .chain(pathext.split(';')
+               .filter(|ext| !ext.is_empty())
+               .map(|ext| format!("{}{}", name, ext.to_lowercase())))
+        .collect()
+}
+
+#[cfg(not(windows))]
+fn executable_names(name: &str) -> Vec<String> {
+    vec![name.to_string()]
+}
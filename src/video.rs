@@ -4,114 +4,771 @@
 use serde::{Serialize, Deserialize};
 use std::process::Command;
 use crate::{Quality, Operation};
+use crate::log::{self, LogLevel};
+use crate::audio;
+use crate::probe::{Color, Mastering};
 
 #[derive(Debug, Clone, Copy, PartialEq, Hash)]
 #[derive(Serialize, Deserialize)]
 #[derive(EnumIter, AsRefStr, EnumString)]
 pub enum VCodec {
+    /// Chosen to suit the container, as `Container::default_vcodec`
+    Auto,
     Copy,
+    /// For old browsers that can't play VP9, in Webm (or Mkv)
+    Vp8,
     Vp9,
-    Av1
+    /// AV1 with libaom
+    Av1,
+    /// AV1 with SVT-AV1, which is much faster than libaom
+    SvtAv1,
+    H264,
+    H265,
 }
 
-pub fn vp9_or_av1(command: &mut Command, operation: &Operation) {
+/// Hardware encoders, used in place of the software encoder for the codec
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum HwAccel {
+    /// NVIDIA NVENC: H264, H265 and (on newer cards) Av1
+    Nvenc,
+    /// Intel/AMD VAAPI on Linux: H264, H265, and Vp9/Av1 where the GPU can
+    Vaapi,
+}
+
+pub const DEFAULT_VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+impl HwAccel {
+    /// The ffmpeg encoder for the codec, if this hardware can do it
+    pub fn encoder(&self, codec: VCodec) -> Option<&'static str> {
+        match (*self, codec) {
+            (HwAccel::Nvenc, VCodec::H264) => Some("h264_nvenc"),
+            (HwAccel::Nvenc, VCodec::H265) => Some("hevc_nvenc"),
+            (HwAccel::Nvenc, VCodec::Av1) => Some("av1_nvenc"),
+            (HwAccel::Vaapi, VCodec::H264) => Some("h264_vaapi"),
+            (HwAccel::Vaapi, VCodec::H265) => Some("hevc_vaapi"),
+            (HwAccel::Vaapi, VCodec::Vp9) => Some("vp9_vaapi"),
+            (HwAccel::Vaapi, VCodec::Av1) => Some("av1_vaapi"),
+            _ => None,
+        }
+    }
+}
+
+/// How the encoder decides how many bits to spend
+#[derive(Debug, Default, Clone, Copy, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum EncodeMode {
+    /// Two passes aiming at the bitrate derived from the video quality.
+    /// Hardware encoders and SvtAv1 aim at it in a single pass.
+    #[default]
+    TwoPass,
+    /// A single pass at constant quality (CRF), with no target bitrate
+    ConstantQuality,
+}
+
+/// Deinterlacing filters, for interlaced sources such as DV and TV captures
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum Deinterlace {
+    /// Fast, and good enough for most footage
+    Yadif,
+    /// Slower, with fewer artifacts on fine detail
+    Bwdif,
+}
+
+impl Deinterlace {
+    /// A frame is made from every field, doubling the frame rate, so that
+    /// motion stays smooth when the fps filter that follows keeps the field
+    /// rate.  When it keeps a lower rate, it just drops the extra frames.
+    pub fn filter(&self) -> &'static str {
+        match *self {
+            Deinterlace::Yadif => "yadif=mode=send_field",
+            Deinterlace::Bwdif => "bwdif=mode=send_field",
+        }
+    }
+}
+
+/// How hard to denoise.  Denoising before compression removes grain that
+/// would otherwise cost bits, but it adds to the encode time: Light and
+/// Medium use hqdn3d, which is cheap, while Strong uses nlmeans, which can
+/// take longer than the encode itself.
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum DenoiseStrength {
+    Light,
+    Medium,
+    Strong,
+}
+
+impl DenoiseStrength {
+    pub fn filter(&self) -> &'static str {
+        match *self {
+            DenoiseStrength::Light => "hqdn3d=2:1.5:3:3",
+            DenoiseStrength::Medium => "hqdn3d=4:3:6:4.5",
+            DenoiseStrength::Strong => "nlmeans=s=3:p=7:r=15",
+        }
+    }
+}
+
+/// The eq filter for the brightness, contrast, saturation and gamma the
+/// operation sets, if it sets any
+pub fn eq_filter(operation: &Operation) -> Option<String> {
+    let settings: Vec<String> = [("brightness", operation.brightness),
+                                 ("contrast", operation.contrast),
+                                 ("saturation", operation.saturation),
+                                 ("gamma", operation.gamma)]
+        .iter()
+        .filter_map(|(name, value)| value.map(|v| format!("{}={}", name, v)))
+        .collect();
+    if settings.is_empty() {
+        None
+    } else {
+        Some(format!("eq={}", settings.join(":")))
+    }
+}
+
+/// How the frame rate is changed to video_fps
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum FpsMode {
+    /// Drop or duplicate frames
+    Drop,
+    /// Make new frames by motion interpolation, which is smooth when
+    /// raising the rate but very slow
+    Interpolate,
+}
+
+impl FpsMode {
+    pub fn filter(&self, fps: (u32, u32)) -> String {
+        match *self {
+            FpsMode::Drop => format!("fps=fps={}/{}", fps.0, fps.1),
+            FpsMode::Interpolate => format!("minterpolate=fps={}/{}:mi_mode=mci",
+                                            fps.0, fps.1),
+        }
+    }
+}
+
+/// A turn or flip of the pixels
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum Transpose {
+    /// A quarter turn clockwise
+    Clock,
+    /// A quarter turn counterclockwise
+    CounterClock,
+    /// A half turn
+    Clock180,
+    /// Mirror left to right
+    FlipH,
+    /// Mirror top to bottom
+    FlipV,
+    /// A quarter turn clockwise, then mirrored top to bottom
+    ClockFlip,
+    /// A quarter turn counterclockwise, then mirrored top to bottom
+    CounterClockFlip,
+}
+
+impl Transpose {
+    /// The direction ffmpeg's transpose filter takes as a number, which
+    /// is what operations used to give
+    pub fn from_code(code: u64) -> Option<Transpose> {
+        match code {
+            0 => Some(Transpose::CounterClockFlip),
+            1 => Some(Transpose::Clock),
+            2 => Some(Transpose::CounterClock),
+            3 => Some(Transpose::ClockFlip),
+            _ => None,
+        }
+    }
+
+    pub fn filter(&self) -> &'static str {
+        match *self {
+            Transpose::Clock => "transpose=clock",
+            Transpose::CounterClock => "transpose=cclock",
+            Transpose::Clock180 => "hflip,vflip",
+            Transpose::FlipH => "hflip",
+            Transpose::FlipV => "vflip",
+            Transpose::ClockFlip => "transpose=clock_flip",
+            Transpose::CounterClockFlip => "transpose=cclock_flip",
+        }
+    }
+
+    /// Whether the width and height swap
+    pub fn turns_quarter(&self) -> bool {
+        !matches!(*self, Transpose::Clock180 | Transpose::FlipH | Transpose::FlipV)
+    }
+}
+
+/// Which encode of the video this is.  The first of two passes only
+/// gathers statistics, so can run faster.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    First,
+    Second,
+    /// The only pass
+    Only,
+}
+
+/// Spacing between keyframes.  Lower values make seeking and stream
+/// segmenting more precise but increase file size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum KeyframeInterval {
+    Frames(u32),
+    /// Converted to frames using the target `video_fps`
+    Seconds(f64),
+}
+
+impl KeyframeInterval {
+    pub fn frames(&self, fps: (u32, u32)) -> u32 {
+        match *self {
+            KeyframeInterval::Frames(f) => f,
+            KeyframeInterval::Seconds(s) =>
+                (s * fps.0 as f64 / fps.1 as f64).round().max(1.0) as u32,
+        }
+    }
+}
+
+impl VCodec {
+    /// The highest CRF value the encoder accepts
+    pub fn max_crf(&self) -> u8 {
+        match *self {
+            VCodec::H264 | VCodec::H265 => 51,
+            _ => 63,
+        }
+    }
+
+    /// The range of the encoder's numbered speed setting, if it has one
+    pub fn speed_range(&self) -> Option<(u8, u8)> {
+        match *self {
+            VCodec::Vp8 => Some((0, 16)),
+            VCodec::Vp9 | VCodec::Av1 => Some((0, 8)),
+            VCodec::SvtAv1 => Some((0, 13)),
+            _ => None,
+        }
+    }
+
+    /// The software encoder ffmpeg uses for the codec
+    pub fn encoder(&self) -> Option<&'static str> {
+        match *self {
+            VCodec::Auto | VCodec::Copy => None,
+            VCodec::Vp8 => Some("libvpx"),
+            VCodec::Vp9 => Some("libvpx-vp9"),
+            VCodec::Av1 => Some("libaom-av1"),
+            VCodec::SvtAv1 => Some("libsvtav1"),
+            VCodec::H264 => Some("libx264"),
+            VCodec::H265 => Some("libx265"),
+        }
+    }
+
+    /// The pixel formats the encoder takes
+    pub fn pix_fmts(&self) -> &'static [&'static str] {
+        match *self {
+            VCodec::Auto | VCodec::Copy => &[],
+            VCodec::Vp8 => &["yuv420p", "yuva420p"],
+            VCodec::Vp9 => &["yuv420p", "yuva420p", "yuv422p", "yuv440p", "yuv444p",
+                             "yuv420p10le", "yuv422p10le", "yuv440p10le", "yuv444p10le",
+                             "yuv420p12le", "yuv422p12le", "yuv440p12le", "yuv444p12le",
+                             "gbrp", "gbrp10le", "gbrp12le"],
+            VCodec::Av1 => &["yuv420p", "yuv422p", "yuv444p", "gray",
+                             "yuv420p10le", "yuv422p10le", "yuv444p10le", "gray10le",
+                             "yuv420p12le", "yuv422p12le", "yuv444p12le", "gray12le"],
+            VCodec::SvtAv1 => &["yuv420p", "yuv420p10le"],
+            VCodec::H264 => &["yuv420p", "yuvj420p", "yuv422p", "yuvj422p", "yuv444p",
+                              "yuvj444p", "nv12", "nv16", "nv21",
+                              "yuv420p10le", "yuv422p10le", "yuv444p10le", "nv20le",
+                              "gray", "gray10le"],
+            VCodec::H265 => &["yuv420p", "yuvj420p", "yuv422p", "yuvj422p", "yuv444p",
+                              "yuvj444p", "gbrp", "yuv420p10le", "yuv422p10le",
+                              "yuv444p10le", "gbrp10le", "yuv420p12le", "yuv422p12le",
+                              "yuv444p12le", "gbrp12le", "gray", "gray10le", "gray12le"],
+        }
+    }
+
+    /// The CRF used when none is given.  Each encoder has its own scale.
+    pub fn default_crf(&self, quality: Quality) -> u8 {
+        match *self {
+            VCodec::Vp8 => 10,
+            VCodec::Vp9 => 31, // always reasonable for me
+            VCodec::Av1 | VCodec::SvtAv1 => 30,
+            VCodec::H264 => h264_crf(quality),
+            VCodec::H265 => h265_crf(quality),
+            VCodec::Auto | VCodec::Copy => 0, // (nothing is encoded)
+        }
+    }
+
+    /// Whether ffmpeg can run the encoder in two passes.  SVT-AV1 has its
+    /// own multi-pass mode that ffmpeg's -pass does not drive, and a copy
+    /// is not encoded at all.
+    pub fn supports_two_pass(&self) -> bool {
+        !matches!(*self, VCodec::SvtAv1 | VCodec::Copy | VCodec::Auto)
+    }
+}
+
+/// Compressing less than this gives no better picture, only a bigger file
+const MIN_COMPRESSION: u64 = 10;
+
+/// Target video bitrate in bits per second
+pub fn target_bitrate(operation: &Operation) -> u32 {
+    wanted_bitrate(operation).min(bitrate_ceiling(operation))
+}
+
+/// The most bitrate worth spending on the output size and frame rate
+pub fn bitrate_ceiling(operation: &Operation) -> u32 {
+    let (width, height) = operation.output_size();
+    (uncompressed_bitrate(operation, width, height) / MIN_COMPRESSION)
+        .min(u32::MAX as u64) as u32
+}
+
+/// Warn if the bitrate from the quality or target size had to be capped
+pub fn warn_if_capped(operation: &Operation) {
+    let wanted = wanted_bitrate(operation);
+    let ceiling = bitrate_ceiling(operation);
+    if wanted > ceiling {
+        println!("WARNING: A video bitrate of {} would be wasted on {}x{}, so it is \
+                  capped at {}", wanted, operation.output_size().0,
+                 operation.output_size().1, ceiling);
+    }
+}
+
+fn wanted_bitrate(operation: &Operation) -> u32 {
+    if let (Some(mb), Some(secs)) = (operation.target_size_mb, operation.output_secs) {
+        // Leave 2% for the container overhead
+        let total_bitrate = mb as f64 * 8_000_000.0 * 0.98 / secs;
+        let audio_bitrate = audio::bitrate(operation.audio_codec, operation.audio_quality)
+            .filter(|_| !operation.silent)
+            .unwrap_or(0) as f64 * 1000.0;
+        let bitrate = (total_bitrate - audio_bitrate).max(0.0) as u32;
+        if log::enabled(LogLevel::Verbose) {
+            println!("bitrate = {} (to fit {} MB)", bitrate, mb);
+        }
+        return bitrate;
+    }
+
+    let (width, height) = operation.output_size();
     let bitrate = {
-        let uncompressed_bitrate = uncompressed_bitrate(operation.video_fps,
-                                                        operation.scale.0 as u32,
-                                                        operation.scale.1 as u32);
-        println!("Uncompressed bitrate = {}", uncompressed_bitrate);
+        let uncompressed_bitrate = uncompressed_bitrate(operation, width, height);
         let compression_factor = compression_factor(operation.video_codec,
                                                     operation.video_quality);
-        println!("Compression factor = {}", compression_factor);
+        if log::enabled(LogLevel::Verbose) {
+            println!("Uncompressed bitrate = {}", uncompressed_bitrate);
+            println!("Compression factor = {}", compression_factor);
+        }
         (uncompressed_bitrate / compression_factor as u64) as u32
     };
-    println!("bitrate = {}", bitrate);
+    if log::enabled(LogLevel::Verbose) {
+        println!("bitrate = {}", bitrate);
+    }
+    bitrate
+}
+
+/// Add the video encoder arguments for a (non-Copy) video codec
+pub fn build_video_codec_args(command: &mut Command, operation: &Operation, pass: Pass) {
+    match operation.hwaccel {
+        Some(HwAccel::Nvenc) => nvenc(command, operation),
+        Some(HwAccel::Vaapi) => vaapi(command, operation),
+        None => vp9_or_av1(command, operation, pass),
+    }
+    // A picture (e.g. an RGB png) would otherwise be encoded as 4:4:4 or
+    // RGB, which many players can't play.  (VAAPI is given nv12.)
+    if operation.still_image.is_some() && operation.pix_fmt.is_none()
+        && operation.hwaccel != Some(HwAccel::Vaapi)
+    {
+        command.arg("-pix_fmt").arg("yuv420p");
+    }
+}
+
+fn nvenc(command: &mut Command, operation: &Operation) {
+    let encoder = HwAccel::Nvenc.encoder(operation.video_codec)
+        .expect("codec was validated against the hardware encoder");
+
+    let keyframe_interval = operation.keyframe_interval
+        .map(|k| k.frames(operation.video_fps))
+        .unwrap_or(240);
+
+    // NVENC's constant quality uses the same scale as x264's CRF
+    let cq = operation.crf.unwrap_or_else(|| h264_crf(operation.video_quality));
+
+    let preset = match operation.video_quality {
+        Quality::VeryLow => "p3",
+        Quality::Low => "p4",
+        Quality::Medium => "p5",
+        Quality::High => "p6",
+        Quality::VeryHigh => "p7",
+    };
+
+    command
+        .arg("-c:v").arg(encoder)
+        .arg("-preset").arg(preset)
+        .arg("-rc").arg("vbr");
+
+    match operation.encode_mode {
+        EncodeMode::TwoPass => {
+            // NVENC does its own two passes within one ffmpeg run
+            let bitrate = target_bitrate(operation);
+            command
+                .arg("-multipass").arg("fullres")
+                .arg("-b:v").arg(&*format!("{}", bitrate))
+                .arg("-maxrate").arg(&*format!("{}", percent(bitrate, operation.maxrate_pct)));
+        },
+        EncodeMode::ConstantQuality => {
+            command
+                .arg("-cq").arg(&*format!("{}", cq))
+                .arg("-b:v").arg("0");
+        },
+    }
+
+    command.arg("-g").arg(&*format!("{}", keyframe_interval));
+}
+
+fn vaapi(command: &mut Command, operation: &Operation) {
+    let encoder = HwAccel::Vaapi.encoder(operation.video_codec)
+        .expect("codec was validated against the hardware encoder");
 
-    let tile_columns = if operation.scale.0 < 640 { 0 }
-    else if operation.scale.0 < 1024 { 1 }
-    else if operation.scale.0 < 2560 { 2 }
-    else { 3 };
+    let keyframe_interval = operation.keyframe_interval
+        .map(|k| k.frames(operation.video_fps))
+        .unwrap_or(240);
 
-    let threads = 16; // always reasonable for me
-    let crf = 31;     // always reasonable for me
+    command.arg("-c:v").arg(encoder);
+
+    match operation.encode_mode {
+        EncodeMode::TwoPass => {
+            // VAAPI has no two-pass, so this is the nearest equivalent
+            let bitrate = target_bitrate(operation);
+            command
+                .arg("-rc_mode").arg("VBR")
+                .arg("-b:v").arg(&*format!("{}", bitrate))
+                .arg("-maxrate").arg(&*format!("{}", percent(bitrate, operation.maxrate_pct)));
+        },
+        EncodeMode::ConstantQuality => {
+            let qp = operation.crf.unwrap_or_else(|| h264_crf(operation.video_quality));
+            command
+                .arg("-rc_mode").arg("CQP")
+                .arg("-qp").arg(&*format!("{}", qp));
+        },
+    }
+
+    command.arg("-g").arg(&*format!("{}", keyframe_interval));
+}
+
+/// Tag the output with the source's colour description
+pub fn color_args(command: &mut Command, color: &Color) {
+    if let Some(ref primaries) = color.primaries {
+        command.arg("-color_primaries").arg(primaries);
+    }
+    if let Some(ref transfer) = color.transfer {
+        command.arg("-color_trc").arg(transfer);
+    }
+    if let Some(ref space) = color.space {
+        command.arg("-colorspace").arg(space);
+    }
+}
+
+/// Whether the encoder is given the HDR mastering metadata.  Others only
+/// keep it if ffmpeg passes it on from the frames itself.
+pub fn takes_mastering(operation: &Operation) -> bool {
+    operation.hwaccel.is_none()
+        && matches!(operation.video_codec, VCodec::H265 | VCodec::SvtAv1)
+}
+
+fn svtav1_params(color: Option<&Color>) -> String {
+    let mut params = "tune=0".to_string();
+    if let Some(m) = color.and_then(|c| c.mastering.as_ref()) {
+        params.push_str(&format!(
+            ":mastering-display=G({},{})B({},{})R({},{})WP({},{})L({},{})",
+            m.green.0, m.green.1, m.blue.0, m.blue.1, m.red.0, m.red.1,
+            m.white.0, m.white.1, m.max_luminance, m.min_luminance));
+    }
+    if let Some((cll, fall)) = color.and_then(|c| c.light_level) {
+        params.push_str(&format!(":content-light={},{}", cll, fall));
+    }
+    params
+}
+
+/// x265 takes chromaticities in units of 0.00002 and luminance in units of
+/// 0.0001 cd/m²
+fn x265_params(color: &Color) -> Option<String> {
+    let mut params: Vec<String> = Vec::new();
+    if let Some(Mastering { red, green, blue, white, max_luminance, min_luminance })
+        = color.mastering
+    {
+        let c = |v: f64| (v * 50000.0).round() as u32;
+        let l = |v: f64| (v * 10000.0).round() as u32;
+        params.push(format!("master-display=G({},{})B({},{})R({},{})WP({},{})L({},{})",
+                            c(green.0), c(green.1), c(blue.0), c(blue.1),
+                            c(red.0), c(red.1), c(white.0), c(white.1),
+                            l(max_luminance), l(min_luminance)));
+    }
+    if let Some((cll, fall)) = color.light_level {
+        params.push(format!("max-cll={},{}", cll, fall));
+    }
+    if params.is_empty() {
+        return None;
+    }
+    params.insert(0, "hdr10=1".to_string());
+    Some(params.join(":"))
+}
+
+/// A percentage of a bitrate, which may be more than u32 can hold
+fn percent(bitrate: u32, pct: u32) -> u64 {
+    bitrate as u64 * pct as u64 / 100
+}
+
+fn vp9_or_av1(command: &mut Command, operation: &Operation, pass: Pass) {
+    let width = operation.output_size().0;
+
+    let threads = operation.threads
+        .unwrap_or_else(|| std::thread::available_parallelism()
+                        .map(|n| n.get() as u32)
+                        .unwrap_or(1))
+        .max(1);
+
+    let keyframe_interval = operation.keyframe_interval
+        .map(|k| k.frames(operation.video_fps))
+        .unwrap_or(240);
+
+    let crf = operation.crf
+        .unwrap_or_else(|| operation.video_codec.default_crf(operation.video_quality));
 
     match operation.video_codec {
+        VCodec::Auto => unreachable!("Auto is resolved before encoding"),
         VCodec::Copy => { },
+        VCodec::Vp8 => {
+            command
+                .arg("-c:v").arg("libvpx")
+                .arg("-quality").arg("good")
+                .arg("-speed").arg(&*format!("{}", speed(operation, pass, vp9_speed(pass, width))));
+        },
         VCodec::Vp9 => {
             command
                 .arg("-c:v").arg("libvpx-vp9")
-                .arg("-quality").arg("good");
+                .arg("-quality").arg("good")
+                .arg("-tile-columns").arg(&*format!("{}", vp9_tile_columns(width, threads)))
+                .arg("-row-mt").arg("1")
+                .arg("-speed").arg(&*format!("{}", speed(operation, pass, vp9_speed(pass, width))));
         },
         VCodec::Av1 => {
             command
                 .arg("-c:v").arg("libaom-av1")
-                .arg("-strict").arg("-2");
+                .arg("-strict").arg("-2")
+                .arg("-tiles").arg(&*format!("{}x1", av1_tile_columns(width, threads)))
+                .arg("-row-mt").arg("1")
+                .arg("-cpu-used").arg(&*format!("{}", speed(operation, pass, aom_cpu_used(pass, width))));
+        },
+        VCodec::SvtAv1 => {
+            command
+                .arg("-c:v").arg("libsvtav1")
+                .arg("-preset").arg(&*format!("{}", speed(operation, pass, svt_preset(operation.video_quality))))
+                // tune for subjective (visual) quality rather than PSNR
+                .arg("-svtav1-params").arg(svtav1_params(operation.source_color.as_ref()));
+        },
+        VCodec::H264 => {
+            command
+                .arg("-c:v").arg("libx264")
+                .arg("-preset").arg(x26x_preset(operation.video_quality));
+        },
+        VCodec::H265 => {
+            command
+                .arg("-c:v").arg("libx265")
+                .arg("-preset").arg(x26x_preset(operation.video_quality))
+                .arg("-tag:v").arg("hvc1"); // so Apple devices will play it
+            if let Some(params) = operation.source_color.as_ref().and_then(x265_params) {
+                command.arg("-x265-params").arg(params);
+            }
+        },
+    }
+
+    match operation.encode_mode {
+        // SVT-AV1 does single pass VBR, given just the bitrate
+        EncodeMode::TwoPass if operation.video_codec == VCodec::SvtAv1 => {
+            command.arg("-b:v").arg(&*format!("{}", target_bitrate(operation)));
+        },
+        EncodeMode::TwoPass => {
+            let bitrate = target_bitrate(operation);
+            command
+                .arg("-b:v").arg(&*format!("{}", bitrate))
+                .arg("-minrate").arg(&*format!("{}", percent(bitrate, operation.minrate_pct)))
+                .arg("-maxrate").arg(&*format!("{}", percent(bitrate, operation.maxrate_pct)));
+        },
+        EncodeMode::ConstantQuality => {
+            // libvpx and libaom only do constant quality with a zero bitrate
+            if matches!(operation.video_codec, VCodec::Vp9 | VCodec::Av1) {
+                command.arg("-b:v").arg("0");
+            }
+            // while libvpx's VP8 needs a bitrate, which it treats as a cap
+            if operation.video_codec == VCodec::Vp8 {
+                command.arg("-b:v").arg(&*format!("{}", target_bitrate(operation)));
+            }
         },
     }
 
     command
-        .arg("-b:v").arg(&*format!("{}", bitrate))
-        .arg("-minrate").arg(&*format!("{}", bitrate * 50 / 100))
-        .arg("-maxrate").arg(&*format!("{}", bitrate * 145 / 100))
-        .arg("-tile-columns").arg(&*format!("{}", tile_columns))
-        .arg("-g").arg("240")        // keyframe spacing
-        .arg("-threads").arg(&*format!("{}", threads))
-        .arg("-crf").arg(&*format!("{}", crf));
+        .arg("-g").arg(&*format!("{}", keyframe_interval))
+        .arg("-threads").arg(&*format!("{}", threads));
+
+    if let Some(ref pix_fmt) = operation.pix_fmt {
+        command.arg("-pix_fmt").arg(pix_fmt);
+    }
+
+    // SVT-AV1, x264 and x265 take a crf as a switch to CRF rate control,
+    // ignoring the bitrate, while libvpx and libaom use it with the
+    // bitrate as constrained quality
+    let crf_ignores_bitrate = matches!(operation.video_codec,
+                                       VCodec::SvtAv1 | VCodec::H264 | VCodec::H265);
+    if !crf_ignores_bitrate || operation.encode_mode == EncodeMode::ConstantQuality {
+        command.arg("-crf").arg(&*format!("{}", crf));
+    }
 }
 
+/// libvpx's tile-columns is the log2 of the number of tile columns, which
+/// it recommends be log2(width/64), to at most 6.  There is no point in more
+/// tiles than threads.
+fn vp9_tile_columns(width: u32, threads: u32) -> u32 {
+    (width / 64).max(1).ilog2()
+        .min(6)
+        .min(threads.ilog2())
+}
+
+/// libaom is given the number of tile columns itself.  This is the largest
+/// power of two that leaves tiles at least 256 pixels wide, and is no more
+/// than the threads.
+fn av1_tile_columns(width: u32, threads: u32) -> u32 {
+    1 << (width / 256).max(1).ilog2()
+        .min(threads.ilog2())
+}
 
-fn uncompressed_bitrate(fps: (u32, u32), x: u32, y: u32) -> u64 {
-    // 24 from bits per pixel (RGB 8-bit)
-    24 * x as u64 * y as u64 * fps.0 as u64 / fps.1 as u64
+fn uncompressed_bitrate(operation: &Operation, x: u32, y: u32) -> u64 {
+    let fps = operation.video_fps;
+    bits_per_pixel(operation.pix_fmt.as_deref()) as u64
+        * x as u64 * y as u64 * fps.0 as u64 / fps.1 as u64
+}
+
+/// Bits per pixel of a raw picture in a pixel format.  Without one we
+/// assume 8-bit 4:2:0, which is what ffmpeg picks for most sources.
+fn bits_per_pixel(pix_fmt: Option<&str>) -> u32 {
+    let pix_fmt = match pix_fmt {
+        Some(p) => p,
+        None => return 12,
+    };
+    let depth = if pix_fmt.starts_with("nv20") || pix_fmt.ends_with("10le")
+        || pix_fmt.ends_with("10be")
+    {
+        10
+    } else if pix_fmt.ends_with("12le") || pix_fmt.ends_with("12be") {
+        12
+    } else {
+        8
+    };
+    // Samples per pixel, doubled: luma plus the subsampled chroma
+    let mut samples = if pix_fmt.starts_with("gray") {
+        2
+    } else if pix_fmt.starts_with("gbr") || pix_fmt.contains("444") {
+        6
+    } else if pix_fmt.contains("422") || pix_fmt.contains("440")
+        || pix_fmt.starts_with("nv16") || pix_fmt.starts_with("nv20")
+    {
+        4
+    } else {
+        3
+    };
+    if pix_fmt.starts_with("yuva") || pix_fmt.starts_with("gbra") {
+        samples += 2;
+    }
+    depth * samples / 2
 }
 
 fn compression_factor(codec: VCodec, quality: Quality) -> u32 {
+    // Of 8-bit 4:2:0 (12 bits per pixel)
     let factor = match quality {
-        Quality::VeryLow => 4600,
-        Quality::Low => 3200, // Fast moving stuff (eye blinks) look a bit wrong, but otherwise looks ok
-        Quality::Medium => 1500, // I cannot tell the difference between this and higher quality
-        Quality::High => 640, // 640 is near average of google recommendations
-        Quality::VeryHigh => 280, // 280 is better than almost all of google recommendations
+        Quality::VeryLow => 2300,
+        Quality::Low => 1600, // Fast moving stuff (eye blinks) look a bit wrong, but otherwise looks ok
+        Quality::Medium => 750, // I cannot tell the difference between this and higher quality
+        Quality::High => 320, // 320 is near average of google recommendations
+        Quality::VeryHigh => 140, // 140 is better than almost all of google recommendations
     };
     match codec {
-        VCodec::Copy => factor,
+        VCodec::Auto | VCodec::Copy => factor,
+        VCodec::Vp8 => factor * 100 / 160, // VP8 needs about 1.6x the bits of VP9
         VCodec::Vp9 => factor,
-        VCodec::Av1 => factor * 100 / 70, // 30% less bits needed for AV1
+        VCodec::Av1 | VCodec::SvtAv1 => factor * 100 / 70, // 30% less bits needed for AV1
+        VCodec::H264 => factor * 100 / 175, // H.264 needs 1.5-2x the bits of VP9
+        VCodec::H265 => factor * 100 / 95, // HEVC needs 40-50% less bits than H.264
     }
 }
 
-/* We no longer get fps.  We instead convert to a target fps during conversion
-const FFPROBE_PATH: &'static str = "/usr/bin/ffprobe";
-pub fn get_fps(input: &str) -> (u32, u32) {
-    let mut command = Command::new(FFPROBE_PATH);
-    command.arg("-v").arg("0")
-        .arg("-of").arg("csv=p=0")
-        .arg("-select_streams").arg("v:0")
-        .arg("-show_entries").arg("stream=r_frame_rate")
-        .arg(input);
+// x264 has its own CRF scale (0-51, default 23)
+fn h264_crf(quality: Quality) -> u8 {
+    match quality {
+        Quality::VeryLow => 30,
+        Quality::Low => 26,
+        Quality::Medium => 23,
+        Quality::High => 20,
+        Quality::VeryHigh => 18,
+    }
+}
 
-    let output = command
-        .output()
-        .expect("failed to execute ffprobe");
+// x265 has the same CRF range as x264, but default 28
+fn h265_crf(quality: Quality) -> u8 {
+    match quality {
+        Quality::VeryLow => 34,
+        Quality::Low => 31,
+        Quality::Medium => 28,
+        Quality::High => 25,
+        Quality::VeryHigh => 22,
+    }
+}
+
+// The pass1_speed or pass2_speed, if given
+fn speed(operation: &Operation, pass: Pass, default: u8) -> u8 {
+    match pass {
+        Pass::First => operation.pass1_speed,
+        _ => operation.pass2_speed,
+    }.unwrap_or(default)
+}
 
-    if ! output.status.success() {
-        let stderr_str = String::from_utf8_lossy(&*output.stderr).to_string();
-        panic!("Failed to run ffprobe.  Stderr follows.\n{}",
-               stderr_str);
+// libvpx -speed, 0 (slowest, best) to 8.  Smaller pictures can afford to
+// be encoded more slowly.
+fn vp9_speed(pass: Pass, width: u32) -> u8 {
+    match pass {
+        Pass::First => 4,
+        _ if width < 1024 => 1,
+        _ => 2,
     }
+}
 
-    let fpsstr = String::from_utf8_lossy(&*output.stdout).trim().to_string();
-    match fpsstr.find('/') {
-        None => {
-            println!("Could not determine FPS, using 30.");
-            return (30,1);
-        },
-        Some(i) => {
-            let numerator = &fpsstr[..i].parse::<u32>().unwrap();
-            let denominator = &fpsstr[i+1..].parse::<u32>().unwrap();
-            return (*numerator, *denominator)
-        }
+// libaom -cpu-used, 0 (slowest, best) to 8.  It is much slower than libvpx
+// at the same setting.
+fn aom_cpu_used(pass: Pass, width: u32) -> u8 {
+    match pass {
+        Pass::First => 6,
+        _ if width < 1024 => 3,
+        _ => 4,
     }
 }
-*/
 
+// SVT-AV1 presets run from 0 (slowest, best) to 13
+fn svt_preset(quality: Quality) -> u8 {
+    match quality {
+        Quality::VeryLow => 12,
+        Quality::Low => 10,
+        Quality::Medium => 8,
+        Quality::High => 6,
+        Quality::VeryHigh => 4,
+    }
+}
+
+fn x26x_preset(quality: Quality) -> &'static str {
+    match quality {
+        Quality::VeryLow => "veryfast",
+        Quality::Low => "faster",
+        Quality::Medium => "medium",
+        Quality::High => "slow",
+        Quality::VeryHigh => "slower",
+    }
+}
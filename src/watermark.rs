@@ -0,0 +1,116 @@
+// © Copyright 2021 Michael Dilger <mike@mikedilger.com>
+// All rights reserved.
+
+use serde::{Serialize, Deserialize};
+use crate::subtitle::filter_escape;
+
+/// Distance in pixels between a watermark and the edges of the picture
+const MARGIN: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Hash, Default)]
+#[derive(Serialize, Deserialize)]
+#[derive(EnumIter, AsRefStr, EnumString)]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+impl Position {
+    /// x and y expressions, given the names ffmpeg uses for the picture
+    /// size and for the size of the thing being placed on it
+    pub fn xy(&self, width: &str, height: &str, w: &str, h: &str) -> (String, String) {
+        let left = format!("{}", MARGIN);
+        let right = format!("{}-{}-{}", width, w, MARGIN);
+        let top = format!("{}", MARGIN);
+        let bottom = format!("{}-{}-{}", height, h, MARGIN);
+        match self {
+            Position::TopLeft => (left, top),
+            Position::TopRight => (right, top),
+            Position::BottomLeft => (left, bottom),
+            Position::BottomRight => (right, bottom),
+            Position::Center => (format!("({}-{})/2", width, w),
+                                 format!("({}-{})/2", height, h)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Watermark {
+    /// An image (e.g. a .png with transparency) placed over the picture at
+    /// its own size
+    Image {
+        path: String,
+        #[serde(default)]
+        position: Position,
+    },
+    /// Text drawn over the picture
+    Text {
+        text: String,
+        /// A fontconfig font name, e.g. "Sans" or "DejaVu Serif:bold"
+        #[serde(default)]
+        font: Option<String>,
+        /// Font size in pixels
+        #[serde(default = "default_font_size")]
+        size: u32,
+        #[serde(default)]
+        position: Position,
+    },
+}
+
+fn default_font_size() -> u32 {
+    24
+}
+
+impl Watermark {
+    /// The image to be given to ffmpeg as a second input, if any
+    pub fn image(&self) -> Option<&str> {
+        match self {
+            Watermark::Image { path, .. } => Some(path),
+            Watermark::Text { .. } => None,
+        }
+    }
+
+    /// The filter that applies the watermark.  For an image this takes two
+    /// inputs, the picture then the image.
+    pub fn filter(&self) -> String {
+        match self {
+            Watermark::Image { position, .. } => {
+                let (x, y) = position.xy("W", "H", "w", "h");
+                format!("overlay={}:{}", x, y)
+            },
+            Watermark::Text { text, font, size, position } => {
+                let (x, y) = position.xy("w", "h", "text_w", "text_h");
+                let mut filter = format!("drawtext=text={}", filter_escape(text));
+                if let Some(font) = font {
+                    filter.push_str(&format!(":font={}", filter_escape(font)));
+                }
+                filter.push_str(&format!(":fontsize={}:fontcolor=white\
+                                          :borderw=2:bordercolor=black@0.6:expansion=none:x={}:y={}",
+                                         size, x, y));
+                filter
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_and_font_are_escaped() {
+        let watermark = Watermark::Text {
+            text: "It's 10:30".to_string(),
+            font: Some("DejaVu Serif:bold".to_string()),
+            size: 24,
+            position: Position::TopLeft,
+        };
+        assert!(watermark.filter().starts_with(
+            r"drawtext=text=It\\\'s 10\\:30:font=DejaVu Serif\\:bold:fontsize=24"));
+    }
+}